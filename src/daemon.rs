@@ -0,0 +1,379 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use failure::Error;
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use prefault::memory;
+use prefault::process::*;
+use prefault::snapshot::*;
+use prefault::store::SnapshotStore;
+use prefault::workers::Workers;
+use prefault::{ProcEvent, ProcMonitor};
+
+/// The number of snapshots that may wait to be prefaulted, snapshots of
+/// further execs are dropped until the queue drains
+const QUEUE_SIZE: usize = 64;
+
+struct TrackedProcess {
+    command: String,
+
+    /// The key of the snapshot, see `Identity`
+    key: String,
+    next_check: Instant,
+
+    /// The set of mapped files at the time of the last snapshot
+    files: Option<HashSet<PathBuf>>,
+}
+
+/// Watches process creation, takes snapshots of tracked processes once they
+/// have been running for a while, and prefaults their snapshots on exec
+pub struct Daemon {
+    /// Snapshots are looked up in all layers, and saved to the writable one
+    store: SnapshotStore,
+    delay: Duration,
+    identity: Identity,
+    verbosity: u8,
+
+    /// Snapshots to be prefaulted, by key, and the keys that are queued or
+    /// being prefaulted, so that repeated execs are only prefaulted once
+    queue: SyncSender<(String, Vec<MappedFile>)>,
+    in_flight: Arc<Mutex<HashSet<String>>>,
+
+    tracked: HashMap<libc::pid_t, TrackedProcess>,
+
+    inotify: Inotify,
+    watches: HashMap<WatchDescriptor, PathBuf>,
+    watched_dirs: HashSet<PathBuf>,
+}
+
+impl Daemon {
+    pub fn new(
+        store: SnapshotStore,
+        delay: Duration,
+        identity: Identity,
+        workers: Workers,
+        verbosity: u8,
+    ) -> Result<Self, Error> {
+        let (queue, jobs) = mpsc::sync_channel::<(String, Vec<MappedFile>)>(QUEUE_SIZE);
+        let in_flight = Arc::new(Mutex::new(HashSet::new()));
+
+        let done = in_flight.clone();
+        thread::spawn(move || {
+            for (command, files) in jobs {
+                for (path, e) in
+                    memory::prefault_file_mappings(&files, memory::Alignment::Page, &workers)
+                {
+                    eprintln!("{}: {}: {}", command, path.display(), e);
+                }
+
+                done.lock().unwrap().remove(&command);
+            }
+        });
+
+        Ok(Daemon {
+            store,
+            delay,
+            identity,
+            verbosity,
+            queue,
+            in_flight,
+            tracked: HashMap::new(),
+            inotify: Inotify::init()?,
+            watches: HashMap::new(),
+            watched_dirs: HashSet::new(),
+        })
+    }
+
+    /// Run until `RUNNING` is cleared. Processes are tracked if `is_tracked`
+    /// matches them, or if a snapshot of them already exists.
+    pub fn run<F: Fn(&Process) -> bool>(&mut self, is_tracked: F) -> Result<(), Error> {
+        let mut monitor = ProcMonitor::new()?;
+
+        self.watch_snapshots()?;
+
+        for process in Process::enumerate()? {
+            if self.should_track(&process, &is_tracked) {
+                self.track(&process);
+            }
+        }
+
+        while crate::RUNNING.load(Ordering::SeqCst) {
+            for event in monitor.read_events(Duration::from_secs(1))? {
+                match event {
+                    ProcEvent::Exec { pid } => {
+                        if let Ok(process) = Process::new(pid) {
+                            if self.should_track(&process, &is_tracked) {
+                                self.prefault(&process);
+                                self.track(&process);
+                            }
+                        }
+                    }
+
+                    ProcEvent::Exit { pid } => {
+                        self.tracked.remove(&pid);
+                    }
+                }
+            }
+
+            self.handle_file_changes();
+            self.check_tracked();
+        }
+
+        Ok(())
+    }
+
+    fn should_track<F: Fn(&Process) -> bool>(&self, process: &Process, is_tracked: &F) -> bool {
+        if process.pid == unsafe { libc::getpid() } {
+            return false;
+        }
+
+        match self.identity.get_process_key(process) {
+            Ok(key) => is_tracked(process) || self.store.find(&key).is_some(),
+
+            Err(_) => false,
+        }
+    }
+
+    fn track(&mut self, process: &Process) {
+        if let (Ok(command), Ok(key)) = (
+            process.get_command(),
+            self.identity.get_process_key(process),
+        ) {
+            if self.verbosity > 0 {
+                println!("Tracking {} ({})", key, process.pid);
+            }
+
+            self.tracked.insert(
+                process.pid,
+                TrackedProcess {
+                    command,
+                    key,
+                    next_check: Instant::now() + self.delay,
+                    files: None,
+                },
+            );
+        }
+    }
+
+    /// Prefault the snapshot of a freshly executed process in the background
+    fn prefault(&self, process: &Process) {
+        let command = match self.identity.get_process_key(process) {
+            Ok(key) => key,
+            Err(_) => return,
+        };
+
+        // already queued or being prefaulted
+        if !self.in_flight.lock().unwrap().insert(command.clone()) {
+            return;
+        }
+
+        let snapshot = self
+            .store
+            .find(&command)
+            .and_then(|path| Snapshot::new_from_file(path).ok());
+
+        let files = match snapshot {
+            Some(snapshot) if snapshot.enabled => snapshot.get_files(),
+
+            _ => {
+                self.in_flight.lock().unwrap().remove(&command);
+                return;
+            }
+        };
+
+        match self.queue.try_send((command.clone(), files)) {
+            Ok(()) => {
+                if self.verbosity > 0 {
+                    println!("Prefaulting {}", command);
+                }
+            }
+
+            Err(_) => {
+                self.in_flight.lock().unwrap().remove(&command);
+
+                if self.verbosity > 0 {
+                    println!("Not prefaulting {}, too many snapshots are queued", command);
+                }
+            }
+        }
+    }
+
+    /// Snapshot tracked processes that have been running for long enough,
+    /// and re-snapshot them whenever their set of mapped files changes
+    fn check_tracked(&mut self) {
+        let now = Instant::now();
+        let mut exited = vec![];
+
+        for (pid, tracked) in self.tracked.iter_mut() {
+            if now < tracked.next_check {
+                continue;
+            }
+
+            tracked.next_check = now + self.delay;
+
+            let process = match Process::new(*pid) {
+                Ok(process) => process,
+
+                Err(_) => {
+                    exited.push(*pid);
+                    continue;
+                }
+            };
+
+            let files = process.get_mapped_files();
+            if tracked.files.as_ref() == Some(&files) {
+                continue;
+            }
+
+            match Snapshot::new_from_process(&process, &self.identity) {
+                Ok(mut snapshot) => {
+                    // keep the state and priority that have been set by the user
+                    let previous = self
+                        .store
+                        .find(&tracked.key)
+                        .and_then(|path| Snapshot::new_from_file(path).ok());
+
+                    if let Some(previous) = previous {
+                        snapshot.set_enabled(previous.enabled);
+                        snapshot.set_priority(previous.priority);
+                    }
+
+                    match snapshot.save_to_file(self.store.get_writable_dir()) {
+                        Ok(path) => {
+                            println!("Wrote {}", path.display());
+
+                            for file in files.iter() {
+                                watch_parent_dir(
+                                    &mut self.inotify,
+                                    &mut self.watches,
+                                    &mut self.watched_dirs,
+                                    file,
+                                );
+                            }
+
+                            tracked.files = Some(files);
+                        }
+
+                        Err(e) => eprintln!("{}: {}", tracked.command, e),
+                    }
+                }
+
+                Err(e) => eprintln!("{}: {}", tracked.command, e),
+            }
+        }
+
+        for pid in exited {
+            self.tracked.remove(&pid);
+        }
+    }
+
+    /// Watch the directories of all files referenced by enabled snapshots, so
+    /// that we notice when a package manager replaces them
+    fn watch_snapshots(&mut self) -> Result<(), Error> {
+        for path in self.store.get_snapshot_paths()? {
+            match Snapshot::new_from_file(&path) {
+                Ok(snapshot) => {
+                    if snapshot.enabled {
+                        for file in snapshot.mappings.keys() {
+                            watch_parent_dir(
+                                &mut self.inotify,
+                                &mut self.watches,
+                                &mut self.watched_dirs,
+                                file,
+                            );
+                        }
+                    }
+                }
+
+                Err(e) => eprintln!("{}: {}", path.display(), e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Schedule a new snapshot for all tracked processes that map a file
+    /// that has been changed on disk
+    fn handle_file_changes(&mut self) {
+        let mut buffer = [0u8; 4096];
+        let mut changed = HashSet::new();
+
+        match self.inotify.read_events(&mut buffer) {
+            Ok(events) => {
+                for event in events {
+                    if let (Some(dir), Some(name)) = (self.watches.get(&event.wd), event.name) {
+                        changed.insert(dir.join(name));
+                    }
+                }
+            }
+
+            Err(e) => eprintln!("Could not read inotify events: {}", e),
+        }
+
+        if changed.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        for tracked in self.tracked.values_mut() {
+            if let Some(ref files) = tracked.files {
+                if let Some(file) = files.iter().find(|f| changed.contains(*f)) {
+                    println!("{}: {} changed on disk", tracked.command, file.display());
+
+                    tracked.files = None;
+                    tracked.next_check = now;
+                }
+            }
+        }
+    }
+}
+
+fn watch_parent_dir(
+    inotify: &mut Inotify,
+    watches: &mut HashMap<WatchDescriptor, PathBuf>,
+    watched_dirs: &mut HashSet<PathBuf>,
+    file: &Path,
+) {
+    if let Some(dir) = file.parent() {
+        if watched_dirs.contains(dir) {
+            return;
+        }
+
+        match inotify.add_watch(
+            dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE | WatchMask::DELETE,
+        ) {
+            Ok(wd) => {
+                watches.insert(wd, dir.to_path_buf());
+                watched_dirs.insert(dir.to_path_buf());
+            }
+
+            Err(e) => eprintln!("{}: {}", dir.display(), e),
+        }
+    }
+}
@@ -0,0 +1,506 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+#![allow(non_local_definitions)]
+
+use failure::{Error, Fail};
+use lazy_static::lazy_static;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+const LD_SO_CACHE_PATH: &str = "/etc/ld.so.cache";
+const LD_SO_CACHE_MAGIC_OLD: &[u8] = b"ld.so-1.7.0";
+const LD_SO_CACHE_MAGIC_NEW: &[u8] = b"glibc-ld.so.cache1.1";
+
+const EM_386: u16 = 3;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+#[derive(Fail, Debug)]
+pub enum ElfError {
+    #[fail(display = "Not an ELF file: {}", _0)]
+    InvalidFile(String),
+
+    #[fail(display = "Malformed ELF file: {}", _0)]
+    Malformed(String),
+
+    #[fail(display = "Invalid or unsupported dynamic linker cache: {}", _0)]
+    InvalidCache(String),
+}
+
+lazy_static! {
+    /// Maps sonames to the paths listed for them in the dynamic linker's cache
+    static ref LD_SO_CACHE: HashMap<String, Vec<PathBuf>> =
+        read_ld_so_cache(LD_SO_CACHE_PATH).unwrap_or_default();
+}
+
+fn read_native_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_cache_string(buf: &[u8], offset: usize) -> Option<String> {
+    let s = buf.get(offset..)?;
+    let end = s.iter().position(|b| *b == 0)?;
+
+    Some(String::from_utf8_lossy(&s[..end]).into_owned())
+}
+
+/// Parse the dynamic linker's cache, as written by ldconfig(8). Supports the
+/// "new" format, either standalone or appended to the "old" format.
+fn read_ld_so_cache<T: AsRef<Path>>(path: T) -> Result<HashMap<String, Vec<PathBuf>>, Error> {
+    let buf = fs::read(path.as_ref())?;
+    let invalid = || ElfError::InvalidCache(path.as_ref().to_string_lossy().into());
+
+    let mut base = 0;
+    if buf.starts_with(LD_SO_CACHE_MAGIC_OLD) {
+        // struct cache_file, followed by an array of 12 byte entries, and the
+        // new format aligned to 8 bytes (ALIGN_CACHE in glibc)
+        let nlibs = read_native_u32(&buf, 12).ok_or_else(invalid)? as usize;
+        base = (16 + nlibs * 12).div_ceil(8) * 8;
+    }
+
+    if !buf[base.min(buf.len())..].starts_with(LD_SO_CACHE_MAGIC_NEW) {
+        return Err(invalid().into());
+    }
+
+    // struct cache_file_new, followed by an array of 24 byte entries; string
+    // offsets are relative to the start of the struct
+    let nlibs = read_native_u32(&buf, base + 20).ok_or_else(invalid)? as usize;
+
+    let mut result: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for i in 0..nlibs {
+        let entry = base + 48 + i * 24;
+
+        let key = read_native_u32(&buf, entry + 4).ok_or_else(invalid)? as usize;
+        let value = read_native_u32(&buf, entry + 8).ok_or_else(invalid)? as usize;
+
+        if let (Some(key), Some(value)) = (
+            read_cache_string(&buf, base + key),
+            read_cache_string(&buf, base + value),
+        ) {
+            result.entry(key).or_default().push(PathBuf::from(value));
+        }
+    }
+
+    Ok(result)
+}
+
+struct Segment {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+/// The dynamic linking information of an ELF executable or shared object
+#[derive(Debug, Clone)]
+pub struct ElfFile {
+    pub path: PathBuf,
+    pub class64: bool,
+    pub little_endian: bool,
+    pub machine: u16,
+
+    pub interpreter: Option<PathBuf>,
+    pub needed: Vec<String>,
+    pub rpath: Vec<String>,
+    pub runpath: Vec<String>,
+}
+
+struct Reader {
+    file: File,
+    path: PathBuf,
+    size: u64,
+    class64: bool,
+    little_endian: bool,
+}
+
+impl Reader {
+    fn malformed(&self) -> ElfError {
+        ElfError::Malformed(self.path.to_string_lossy().into())
+    }
+
+    /// Read `len` bytes at `offset`, which have to lie within the file
+    fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => {}
+            _ => return Err(self.malformed().into()),
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        self.file.read_exact_at(&mut buf, offset)?;
+
+        Ok(buf)
+    }
+
+    fn uint(&self, buf: &[u8]) -> u64 {
+        let mut result = 0u64;
+
+        if self.little_endian {
+            for b in buf.iter().rev() {
+                result = (result << 8) | u64::from(*b);
+            }
+        } else {
+            for b in buf.iter() {
+                result = (result << 8) | u64::from(*b);
+            }
+        }
+
+        result
+    }
+
+    /// Read a native word (32 or 64 bit, depending on the ELF class)
+    fn word(&self, buf: &[u8], offset: usize) -> u64 {
+        if self.class64 {
+            self.uint(&buf[offset..offset + 8])
+        } else {
+            self.uint(&buf[offset..offset + 4])
+        }
+    }
+
+    fn string(&self, offset: u64) -> Result<String, Error> {
+        let mut result = vec![];
+        let mut offset = offset;
+
+        loop {
+            let mut buf = [0u8; 256];
+            let len = self.file.read_at(&mut buf, offset)?;
+            if len == 0 {
+                break;
+            }
+
+            match buf[..len].iter().position(|b| *b == 0) {
+                Some(end) => {
+                    result.extend_from_slice(&buf[..end]);
+                    break;
+                }
+
+                None => result.extend_from_slice(&buf[..len]),
+            }
+
+            offset += len as u64;
+        }
+
+        Ok(String::from_utf8_lossy(&result).into_owned())
+    }
+}
+
+impl ElfFile {
+    pub fn parse<T: AsRef<Path>>(path: T) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+
+        let mut ident = [0u8; 16];
+        file.read_exact_at(&mut ident, 0)
+            .map_err(|_| ElfError::InvalidFile(path.to_string_lossy().into()))?;
+
+        if &ident[0..4] != b"\x7fELF" || ident[4] == 0 || ident[4] > 2 {
+            return Err(ElfError::InvalidFile(path.to_string_lossy().into()).into());
+        }
+
+        let reader = Reader {
+            size: file.metadata()?.len(),
+            file,
+            path: path.to_path_buf(),
+            class64: ident[4] == 2,
+            little_endian: ident[5] == 1,
+        };
+
+        let header = reader.read(0, if reader.class64 { 64 } else { 52 })?;
+        let machine = reader.uint(&header[18..20]) as u16;
+
+        let (phoff, phentsize, phnum) = if reader.class64 {
+            (
+                reader.uint(&header[32..40]),
+                reader.uint(&header[54..56]),
+                reader.uint(&header[56..58]),
+            )
+        } else {
+            (
+                reader.uint(&header[28..32]),
+                reader.uint(&header[42..44]),
+                reader.uint(&header[44..46]),
+            )
+        };
+
+        // the fields of a program header that are read below
+        if phentsize < if reader.class64 { 56 } else { 32 } {
+            return Err(reader.malformed().into());
+        }
+
+        let table_size = phnum
+            .checked_mul(phentsize)
+            .ok_or_else(|| reader.malformed())?;
+        let table = reader.read(phoff, table_size)?;
+
+        let mut segments = vec![];
+        for ph in table.chunks_exact(phentsize as usize) {
+            let segment = if reader.class64 {
+                Segment {
+                    kind: reader.uint(&ph[0..4]) as u32,
+                    offset: reader.uint(&ph[8..16]),
+                    vaddr: reader.uint(&ph[16..24]),
+                    filesz: reader.uint(&ph[32..40]),
+                }
+            } else {
+                Segment {
+                    kind: reader.uint(&ph[0..4]) as u32,
+                    offset: reader.uint(&ph[4..8]),
+                    vaddr: reader.uint(&ph[8..12]),
+                    filesz: reader.uint(&ph[16..20]),
+                }
+            };
+
+            segments.push(segment);
+        }
+
+        let mut result = ElfFile {
+            path: path.to_path_buf(),
+            class64: reader.class64,
+            little_endian: reader.little_endian,
+            machine,
+            interpreter: None,
+            needed: vec![],
+            rpath: vec![],
+            runpath: vec![],
+        };
+
+        if let Some(interp) = segments.iter().find(|s| s.kind == PT_INTERP) {
+            result.interpreter = Some(PathBuf::from(reader.string(interp.offset)?));
+        }
+
+        let dynamic = match segments.iter().find(|s| s.kind == PT_DYNAMIC) {
+            Some(dynamic) => dynamic,
+
+            // statically linked
+            None => return Ok(result),
+        };
+
+        let entry_size = if reader.class64 { 16 } else { 8 };
+        let table = reader.read(dynamic.offset, dynamic.filesz)?;
+
+        let mut strtab = None;
+        let mut entries = vec![];
+        for entry in table.chunks_exact(entry_size) {
+            let tag = reader.word(entry, 0);
+            let value = reader.word(entry, entry_size / 2);
+
+            match tag {
+                DT_NULL => break,
+                DT_STRTAB => strtab = Some(value),
+                DT_NEEDED | DT_RPATH | DT_RUNPATH => entries.push((tag, value)),
+                _ => {}
+            }
+        }
+
+        if entries.is_empty() {
+            return Ok(result);
+        }
+
+        // DT_STRTAB holds a virtual address, translate it to a file offset
+        let strtab = strtab
+            .and_then(|vaddr| {
+                segments
+                    .iter()
+                    .find(|s| {
+                        s.kind == PT_LOAD
+                            && s.vaddr <= vaddr
+                            && s.vaddr.checked_add(s.filesz).is_some_and(|end| vaddr < end)
+                    })
+                    .and_then(|s| (vaddr - s.vaddr).checked_add(s.offset))
+            })
+            .ok_or_else(|| reader.malformed())?;
+
+        for (tag, value) in entries {
+            let offset = strtab
+                .checked_add(value)
+                .ok_or_else(|| reader.malformed())?;
+            let string = reader.string(offset)?;
+
+            match tag {
+                DT_NEEDED => result.needed.push(string),
+                DT_RPATH => result
+                    .rpath
+                    .extend(string.split(':').map(|s| s.to_string())),
+                DT_RUNPATH => result
+                    .runpath
+                    .extend(string.split(':').map(|s| s.to_string())),
+                _ => {}
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Whether `other` can be loaded into the same process as this object
+    pub fn is_compatible(&self, other: &ElfFile) -> bool {
+        self.class64 == other.class64
+            && self.little_endian == other.little_endian
+            && self.machine == other.machine
+    }
+
+    fn get_platform(&self) -> &'static str {
+        match self.machine {
+            EM_X86_64 => "x86_64",
+            EM_386 => "i686",
+            EM_AARCH64 => "aarch64",
+            EM_ARM => "arm",
+            _ => "",
+        }
+    }
+
+    fn get_multiarch_triplet(&self) -> Option<&'static str> {
+        match self.machine {
+            EM_X86_64 => Some("x86_64-linux-gnu"),
+            EM_386 => Some("i386-linux-gnu"),
+            EM_AARCH64 => Some("aarch64-linux-gnu"),
+            EM_ARM => Some("arm-linux-gnueabihf"),
+            _ => None,
+        }
+    }
+
+    /// The trusted directories searched by the dynamic linker
+    fn get_default_dirs(&self) -> Vec<PathBuf> {
+        let mut result = vec![];
+
+        if let Some(triplet) = self.get_multiarch_triplet() {
+            result.push(Path::new("/lib").join(triplet));
+            result.push(Path::new("/usr/lib").join(triplet));
+        }
+
+        if self.class64 {
+            result.push(PathBuf::from("/lib64"));
+            result.push(PathBuf::from("/usr/lib64"));
+        }
+
+        result.push(PathBuf::from("/lib"));
+        result.push(PathBuf::from("/usr/lib"));
+
+        result
+    }
+
+    /// Expand dynamic string tokens in a DT_RPATH or DT_RUNPATH entry
+    fn expand_search_path(&self, dir: &str) -> PathBuf {
+        let origin = self
+            .path
+            .parent()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let lib = if self.class64 { "lib64" } else { "lib" };
+
+        PathBuf::from(
+            dir.replace("${ORIGIN}", &origin)
+                .replace("$ORIGIN", &origin)
+                .replace("${LIB}", lib)
+                .replace("$LIB", lib)
+                .replace("${PLATFORM}", self.get_platform())
+                .replace("$PLATFORM", self.get_platform()),
+        )
+    }
+
+    /// Resolve a DT_NEEDED entry of this object the way the dynamic linker does
+    pub fn resolve_library(&self, name: &str) -> Option<PathBuf> {
+        if name.contains('/') {
+            return Some(PathBuf::from(name));
+        }
+
+        let mut dirs = vec![];
+
+        // DT_RPATH is ignored if DT_RUNPATH is present
+        if self.runpath.is_empty() {
+            dirs.extend(self.rpath.iter().map(|d| self.expand_search_path(d)));
+        }
+
+        dirs.extend(self.runpath.iter().map(|d| self.expand_search_path(d)));
+
+        let mut candidates: Vec<PathBuf> = dirs.iter().map(|d| d.join(name)).collect();
+
+        if let Some(cached) = LD_SO_CACHE.get(name) {
+            candidates.extend(cached.iter().cloned());
+        }
+
+        candidates.extend(self.get_default_dirs().iter().map(|d| d.join(name)));
+
+        candidates.into_iter().find(|candidate| {
+            ElfFile::parse(candidate)
+                .map(|elf| self.is_compatible(&elf))
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// Resolve the transitive set of shared libraries required by `path`,
+/// including its program interpreter. Returns the canonicalized paths of the
+/// libraries, and the names of all libraries that could not be found, or
+/// whose dependencies could not be read.
+pub fn resolve_dependencies<T: AsRef<Path>>(
+    path: T,
+) -> Result<(BTreeSet<PathBuf>, BTreeSet<String>), Error> {
+    let mut resolved = BTreeSet::new();
+    let mut unresolved = BTreeSet::new();
+
+    let elf = ElfFile::parse(path.as_ref())?;
+
+    if let Some(ref interpreter) = elf.interpreter {
+        resolved.insert(fs::canonicalize(interpreter).unwrap_or_else(|_| interpreter.clone()));
+    }
+
+    let mut queue = VecDeque::new();
+    queue.push_back(elf);
+
+    while let Some(elf) = queue.pop_front() {
+        for name in elf.needed.iter() {
+            match elf.resolve_library(name) {
+                Some(library) => {
+                    let library = fs::canonicalize(&library).unwrap_or(library);
+
+                    if resolved.insert(library.clone()) {
+                        match ElfFile::parse(&library) {
+                            Ok(dependency) => queue.push_back(dependency),
+                            Err(e) => {
+                                unresolved.insert(format!("{}: {}", library.display(), e));
+                            }
+                        }
+                    }
+                }
+
+                None => {
+                    unresolved.insert(name.clone());
+                }
+            }
+        }
+    }
+
+    Ok((resolved, unresolved))
+}
@@ -18,6 +18,8 @@
     along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+#![allow(non_local_definitions)]
+
 use failure::Error;
 use std::collections::HashSet;
 use std::fs;
@@ -25,6 +27,8 @@ use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::path::PathBuf;
 
+use crate::snapshot::MappedFile;
+
 // #[derive(Fail, Debug)]
 // pub enum FileListError {
 //     #[fail(
@@ -36,6 +40,9 @@ use std::path::PathBuf;
 
 pub struct FileList {
     pub files: HashSet<PathBuf>,
+
+    /// Set with a `# priority: <n>` line, see `Snapshot::priority`
+    pub priority: i32,
 }
 
 impl FileList {
@@ -46,12 +53,33 @@ impl FileList {
         // file.read_line(&mut header)?;
 
         let mut files = HashSet::new();
+        let mut priority = 0;
 
         for l in file.lines() {
-            let value = PathBuf::from(&l?);
-            files.insert(value);
+            let l = l?;
+            let l = l.trim();
+
+            if let Some(comment) = l.strip_prefix('#') {
+                if let Some(value) = comment.trim().strip_prefix("priority:") {
+                    priority = value.trim().parse()?;
+                }
+
+                continue;
+            }
+
+            if !l.is_empty() {
+                files.insert(PathBuf::from(l));
+            }
         }
 
-        Ok(FileList { files })
+        Ok(FileList { files, priority })
+    }
+
+    /// Static file lists always cover whole files
+    pub fn get_mapped_files(&self) -> Vec<MappedFile> {
+        self.files
+            .iter()
+            .map(|f| MappedFile::new(f, vec![]))
+            .collect()
     }
 }
@@ -0,0 +1,798 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Filter expressions, like `comm~=^firefox and not (size>100M or mtime>7d)`
+//!
+//! A predicate is a field, an operator and a value. Text fields support `=`
+//! and `!=` with glob patterns, and `~=` with regular expressions; numeric
+//! fields support `=`, `!=`, `<`, `<=`, `>` and `>=`. Predicates may be
+//! combined with `and`, `or`, `not` and parentheses. Values that contain
+//! whitespace or parentheses have to be quoted.
+
+#![allow(non_local_definitions)]
+
+use failure::Fail;
+use regex::Regex;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::process::Process;
+use crate::snapshot::{Identity, Snapshot};
+use crate::util;
+
+#[derive(Fail, Debug)]
+pub enum FilterError {
+    #[fail(display = "Unexpected end of filter expression")]
+    UnexpectedEnd,
+
+    #[fail(display = "Unexpected '{}' at position {}", token, position)]
+    UnexpectedToken { token: String, position: usize },
+
+    #[fail(display = "Unknown filter field '{}'", _0)]
+    UnknownField(String),
+
+    #[fail(display = "Operator '{}' is not supported for '{}'", op, field)]
+    UnsupportedOperator { field: String, op: String },
+
+    #[fail(display = "Invalid value '{}' for '{}': {}", value, field, msg)]
+    InvalidValue {
+        field: String,
+        value: String,
+        msg: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Comm,
+    Hash,
+    Exe,
+    Cmdline,
+    Uid,
+    Cgroup,
+    Unit,
+    Parent,
+    Path,
+    Size,
+    Enabled,
+    Mtime,
+    Priority,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Number,
+    Bool,
+}
+
+impl Field {
+    fn kind(self) -> Kind {
+        match self {
+            Field::Comm
+            | Field::Hash
+            | Field::Exe
+            | Field::Cmdline
+            | Field::Cgroup
+            | Field::Unit
+            | Field::Parent
+            | Field::Path => Kind::Text,
+
+            Field::Uid | Field::Size | Field::Mtime | Field::Priority => Kind::Number,
+
+            Field::Enabled => Kind::Bool,
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "comm" => Ok(Field::Comm),
+            "hash" => Ok(Field::Hash),
+            "exe" => Ok(Field::Exe),
+            "cmdline" => Ok(Field::Cmdline),
+            "uid" => Ok(Field::Uid),
+            "cgroup" => Ok(Field::Cgroup),
+            "unit" => Ok(Field::Unit),
+            "parent" => Ok(Field::Parent),
+            "path" => Ok(Field::Path),
+            "size" => Ok(Field::Size),
+            "enabled" => Ok(Field::Enabled),
+            "mtime" => Ok(Field::Mtime),
+            "priority" => Ok(Field::Priority),
+
+            _ => Err(FilterError::UnknownField(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Field::Comm => "comm",
+            Field::Hash => "hash",
+            Field::Exe => "exe",
+            Field::Cmdline => "cmdline",
+            Field::Uid => "uid",
+            Field::Cgroup => "cgroup",
+            Field::Unit => "unit",
+            Field::Parent => "parent",
+            Field::Path => "path",
+            Field::Size => "size",
+            Field::Enabled => "enabled",
+            Field::Mtime => "mtime",
+            Field::Priority => "priority",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// The value of a field of the thing a filter is evaluated against
+#[derive(Debug, Clone)]
+pub enum FieldValue {
+    /// Matches if any of the strings matches
+    Text(Vec<String>),
+    Number(i64),
+    Bool(bool),
+}
+
+/// Something a filter can be evaluated against. Fields that are not known
+/// never match.
+pub trait Subject {
+    fn get_field(&self, field: Field) -> Option<FieldValue>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Match,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+const OPERATORS: &[(&str, Op)] = &[
+    ("~=", Op::Match),
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("=", Op::Eq),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+#[derive(Debug, Clone)]
+enum Value {
+    Glob(glob::Pattern),
+    Regex(Regex),
+    Number(i64),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    field: Field,
+    op: Op,
+    value: Value,
+}
+
+/// A parsed filter expression
+#[derive(Debug, Clone)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Predicate(Predicate),
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            input: s.chars().collect(),
+            pos: 0,
+        };
+
+        let result = parser.parse_or()?;
+
+        parser.skip_whitespace();
+        if parser.pos < parser.input.len() {
+            return Err(parser.unexpected());
+        }
+
+        Ok(result)
+    }
+}
+
+impl Filter {
+    pub fn matches<S: Subject>(&self, subject: &S) -> bool {
+        match self {
+            Filter::And(a, b) => a.matches(subject) && b.matches(subject),
+            Filter::Or(a, b) => a.matches(subject) || b.matches(subject),
+            Filter::Not(a) => !a.matches(subject),
+            Filter::Predicate(p) => p.matches(subject),
+        }
+    }
+}
+
+impl Predicate {
+    fn matches<S: Subject>(&self, subject: &S) -> bool {
+        let actual = match subject.get_field(self.field) {
+            Some(actual) => actual,
+
+            None => return false,
+        };
+
+        match (actual, &self.value) {
+            (FieldValue::Text(texts), Value::Glob(pattern)) => {
+                let found = texts.iter().any(|t| pattern.matches(t));
+
+                if self.op == Op::Ne {
+                    !found
+                } else {
+                    found
+                }
+            }
+
+            (FieldValue::Text(texts), Value::Regex(regex)) => {
+                texts.iter().any(|t| regex.is_match(t))
+            }
+
+            (FieldValue::Number(actual), Value::Number(expected)) => match self.op {
+                Op::Eq => actual == *expected,
+                Op::Ne => actual != *expected,
+                Op::Lt => actual < *expected,
+                Op::Le => actual <= *expected,
+                Op::Gt => actual > *expected,
+                Op::Ge => actual >= *expected,
+                Op::Match => false,
+            },
+
+            (FieldValue::Bool(actual), Value::Bool(expected)) => {
+                if self.op == Op::Ne {
+                    actual != *expected
+                } else {
+                    actual == *expected
+                }
+            }
+
+            _ => false,
+        }
+    }
+}
+
+fn parse_value(field: Field, op: Op, value: &str) -> Result<Value, FilterError> {
+    let supported = match field.kind() {
+        Kind::Text => op == Op::Eq || op == Op::Ne || op == Op::Match,
+        Kind::Number => op != Op::Match,
+        Kind::Bool => op == Op::Eq || op == Op::Ne,
+    };
+
+    if !supported {
+        let op = OPERATORS.iter().find(|(_, o)| *o == op).unwrap().0;
+
+        return Err(FilterError::UnsupportedOperator {
+            field: field.to_string(),
+            op: op.into(),
+        });
+    }
+
+    let invalid = |msg: String| FilterError::InvalidValue {
+        field: field.to_string(),
+        value: value.into(),
+        msg,
+    };
+
+    match field {
+        Field::Comm
+        | Field::Hash
+        | Field::Exe
+        | Field::Cmdline
+        | Field::Cgroup
+        | Field::Unit
+        | Field::Parent
+        | Field::Path => {
+            if op == Op::Match {
+                Regex::new(value)
+                    .map(Value::Regex)
+                    .map_err(|e| invalid(e.to_string()))
+            } else {
+                glob::Pattern::new(value)
+                    .map(Value::Glob)
+                    .map_err(|e| invalid(e.to_string()))
+            }
+        }
+
+        Field::Size => util::parse_size(value)
+            .map(|size| Value::Number(size as i64))
+            .ok_or_else(|| invalid("expected a size like 100M".into())),
+
+        Field::Mtime => util::parse_duration(value)
+            .map(Value::Number)
+            .ok_or_else(|| invalid("expected an age like 12h or 7d".into())),
+
+        Field::Uid | Field::Priority => value
+            .parse()
+            .map(Value::Number)
+            .map_err(|e: std::num::ParseIntError| invalid(e.to_string())),
+
+        Field::Enabled => value
+            .parse()
+            .map(Value::Bool)
+            .map_err(|e: std::str::ParseBoolError| invalid(e.to_string())),
+    }
+}
+
+struct Parser {
+    input: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.input.len() && self.input[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    /// Report the word at the current position
+    fn unexpected(&self) -> FilterError {
+        match self.input.get(self.pos) {
+            Some(c) if *c == '(' || *c == ')' => FilterError::UnexpectedToken {
+                token: c.to_string(),
+                position: self.pos + 1,
+            },
+
+            Some(_) => FilterError::UnexpectedToken {
+                token: self.input[self.pos..]
+                    .iter()
+                    .take_while(|c| !c.is_whitespace() && **c != '(' && **c != ')')
+                    .collect(),
+                position: self.pos + 1,
+            },
+
+            None => FilterError::UnexpectedEnd,
+        }
+    }
+
+    fn peek_identifier(&self) -> String {
+        self.input[self.pos..]
+            .iter()
+            .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+            .collect()
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        self.skip_whitespace();
+
+        if self.peek_identifier() == keyword {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_char(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+
+        if self.input.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Filter, FilterError> {
+        let mut result = self.parse_and()?;
+
+        while self.eat_keyword("or") {
+            result = Filter::Or(Box::new(result), Box::new(self.parse_and()?));
+        }
+
+        Ok(result)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, FilterError> {
+        let mut result = self.parse_unary()?;
+
+        while self.eat_keyword("and") {
+            result = Filter::And(Box::new(result), Box::new(self.parse_unary()?));
+        }
+
+        Ok(result)
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter, FilterError> {
+        if self.eat_keyword("not") {
+            return Ok(Filter::Not(Box::new(self.parse_unary()?)));
+        }
+
+        if self.eat_char('(') {
+            let result = self.parse_or()?;
+
+            if !self.eat_char(')') {
+                return Err(self.unexpected());
+            }
+
+            return Ok(result);
+        }
+
+        self.parse_predicate()
+    }
+
+    fn parse_predicate(&mut self) -> Result<Filter, FilterError> {
+        self.skip_whitespace();
+
+        let name = self.peek_identifier();
+        if name.is_empty() {
+            return Err(self.unexpected());
+        }
+
+        let field: Field = name.parse()?;
+        self.pos += name.len();
+
+        self.skip_whitespace();
+        let rest: String = self.input[self.pos..].iter().take(2).collect();
+        let (op_str, op) = OPERATORS
+            .iter()
+            .find(|(s, _)| rest.starts_with(s))
+            .cloned()
+            .ok_or_else(|| self.unexpected())?;
+        self.pos += op_str.len();
+
+        self.skip_whitespace();
+        let value = self.parse_value()?;
+
+        Ok(Filter::Predicate(Predicate {
+            field,
+            op,
+            value: parse_value(field, op, &value)?,
+        }))
+    }
+
+    /// A quoted string, or everything up to the next whitespace or `)`
+    fn parse_value(&mut self) -> Result<String, FilterError> {
+        match self.input.get(self.pos) {
+            Some(&quote) if quote == '"' || quote == '\'' => {
+                let start = self.pos + 1;
+                let end = self.input[start..]
+                    .iter()
+                    .position(|c| *c == quote)
+                    .map(|i| start + i)
+                    .ok_or(FilterError::UnexpectedEnd)?;
+
+                self.pos = end + 1;
+                Ok(self.input[start..end].iter().collect())
+            }
+
+            Some(_) => {
+                let start = self.pos;
+                while self.pos < self.input.len()
+                    && !self.input[self.pos].is_whitespace()
+                    && self.input[self.pos] != ')'
+                {
+                    self.pos += 1;
+                }
+
+                if self.pos == start {
+                    return Err(self.unexpected());
+                }
+
+                Ok(self.input[start..self.pos].iter().collect())
+            }
+
+            None => Err(FilterError::UnexpectedEnd),
+        }
+    }
+}
+
+fn get_age(timestamp: i64) -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+
+    now - timestamp
+}
+
+fn to_strings<'a, I: Iterator<Item = &'a PathBuf>>(paths: I) -> Vec<String> {
+    paths.map(|p| p.to_string_lossy().to_string()).collect()
+}
+
+impl Subject for Snapshot {
+    fn get_field(&self, field: Field) -> Option<FieldValue> {
+        match field {
+            Field::Comm => Some(FieldValue::Text(vec![self.command.clone()])),
+            Field::Hash => Some(FieldValue::Text(vec![self.get_hash().to_string()])),
+            Field::Exe => self
+                .guess_executable()
+                .map(|exe| FieldValue::Text(vec![exe.to_string_lossy().to_string()])),
+            Field::Cmdline => self
+                .cmdline
+                .as_ref()
+                .map(|args| FieldValue::Text(vec![args.join(" ")])),
+            Field::Cgroup => self
+                .cgroup
+                .as_ref()
+                .map(|cgroup| FieldValue::Text(vec![cgroup.clone()])),
+            Field::Unit => self
+                .unit
+                .as_ref()
+                .map(|unit| FieldValue::Text(vec![unit.clone()])),
+            Field::Path => Some(FieldValue::Text(to_strings(self.mappings.keys()))),
+            Field::Size => Some(FieldValue::Number(
+                self.mappings.values().map(|f| f.size as i64).sum(),
+            )),
+            Field::Enabled => Some(FieldValue::Bool(self.enabled)),
+            Field::Mtime => self
+                .captured_at
+                .map(|t| FieldValue::Number(get_age(t as i64))),
+            Field::Priority => Some(FieldValue::Number(self.priority as i64)),
+
+            // not recorded in snapshots
+            Field::Uid | Field::Parent => None,
+        }
+    }
+}
+
+/// A running process, the hash field is computed from the key that its
+/// snapshot has with `identity`, like the hash of a snapshot
+pub struct ProcessSubject<'a> {
+    pub process: &'a Process,
+    pub identity: &'a Identity,
+}
+
+impl<'a> Subject for ProcessSubject<'a> {
+    fn get_field(&self, field: Field) -> Option<FieldValue> {
+        let process = self.process;
+
+        match field {
+            Field::Comm => process
+                .get_command()
+                .ok()
+                .map(|c| FieldValue::Text(vec![c])),
+            Field::Hash => self
+                .identity
+                .get_process_key(process)
+                .ok()
+                .map(|key| FieldValue::Text(vec![util::hash_string(&key)])),
+            Field::Exe => process
+                .get_exe()
+                .ok()
+                .map(|exe| FieldValue::Text(vec![exe.to_string_lossy().to_string()])),
+            Field::Cmdline => process
+                .get_cmdline()
+                .ok()
+                .map(|args| FieldValue::Text(vec![args.join(" ")])),
+            Field::Uid => process
+                .get_uid()
+                .ok()
+                .map(|uid| FieldValue::Number(uid as i64)),
+            Field::Cgroup => process
+                .get_cgroup()
+                .ok()
+                .map(|cgroup| FieldValue::Text(vec![cgroup])),
+            Field::Unit => process
+                .get_unit()
+                .ok()
+                .map(|unit| FieldValue::Text(vec![unit])),
+            Field::Parent => process
+                .get_parent_command()
+                .ok()
+                .map(|parent| FieldValue::Text(vec![parent])),
+            Field::Path => Some(FieldValue::Text(to_strings(
+                process.get_mapped_files().iter(),
+            ))),
+            Field::Size => Some(FieldValue::Number(
+                process
+                    .get_mapped_files()
+                    .iter()
+                    .filter_map(|f| fs::metadata(f).ok())
+                    .map(|m| m.len() as i64)
+                    .sum(),
+            )),
+
+            // only snapshots are enabled and have a capture time and a priority
+            Field::Enabled | Field::Mtime | Field::Priority => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A subject with fixed field values
+    struct Fields(Vec<(Field, FieldValue)>);
+
+    impl Subject for Fields {
+        fn get_field(&self, field: Field) -> Option<FieldValue> {
+            self.0
+                .iter()
+                .find(|(f, _)| *f == field)
+                .map(|(_, value)| value.clone())
+        }
+    }
+
+    fn text(s: &str) -> FieldValue {
+        FieldValue::Text(vec![s.into()])
+    }
+
+    fn firefox() -> Fields {
+        Fields(vec![
+            (Field::Comm, text("firefox")),
+            (
+                Field::Path,
+                FieldValue::Text(vec![
+                    "/usr/lib/firefox/libxul.so".into(),
+                    "/opt/My Apps/lib (old).so".into(),
+                ]),
+            ),
+            (Field::Uid, FieldValue::Number(1000)),
+            (Field::Size, FieldValue::Number(200 * 1024 * 1024)),
+            (Field::Mtime, FieldValue::Number(3 * 24 * 60 * 60)),
+            (Field::Enabled, FieldValue::Bool(true)),
+        ])
+    }
+
+    fn matches(filter: &str) -> bool {
+        filter
+            .parse::<Filter>()
+            .unwrap_or_else(|e| panic!("{}: {}", filter, e))
+            .matches(&firefox())
+    }
+
+    fn error(filter: &str) -> String {
+        match filter.parse::<Filter>() {
+            Ok(_) => panic!("{}: parsed", filter),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(matches("comm=firefox or comm=chrome and uid=0"));
+        assert!(!matches("(comm=firefox or comm=chrome) and uid=0"));
+        assert!(matches("comm=chrome and uid=0 or uid=1000"));
+        assert!(!matches("comm=chrome and (uid=0 or uid=1000)"));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        assert!(!matches("not comm=firefox"));
+        assert!(matches("not not comm=firefox"));
+        assert!(!matches("not comm=firefox and uid=1000"));
+        assert!(matches("not (comm=firefox and uid=0)"));
+        assert!(matches("comm!=chrome"));
+    }
+
+    #[test]
+    fn text_fields_match_globs_and_regexes() {
+        assert!(matches("comm=fire*"));
+        assert!(!matches("comm=fire"));
+        assert!(matches("comm~=^fire"));
+        assert!(!matches("comm~=^fox"));
+        assert!(matches("path=*/libxul.so"));
+        assert!(!matches("path!=*/libxul.so"));
+    }
+
+    #[test]
+    fn quoted_values_may_contain_whitespace_and_parentheses() {
+        assert!(matches("path=\"/opt/My Apps/*\""));
+        assert!(matches("path='*(old).so'"));
+        assert!(matches("(path='/opt/My Apps/lib (old).so')"));
+        assert!(!matches("path=\"/opt/My Apps\""));
+    }
+
+    #[test]
+    fn numbers_take_units() {
+        assert!(matches("size>100M"));
+        assert!(matches("size<=1G"));
+        assert!(!matches("size<200M"));
+        assert!(matches("size=204800K"));
+        assert!(matches("mtime>2d and mtime<1w"));
+        assert!(!matches("mtime>72h"));
+        assert!(matches("uid>=1000 and enabled=true"));
+    }
+
+    #[test]
+    fn unknown_fields_never_match() {
+        assert!(!matches("priority=0"));
+        assert!(matches("not priority=0"));
+    }
+
+    #[test]
+    fn process_hash_follows_the_identity() {
+        let process = Process::new(std::process::id() as libc::pid_t).unwrap();
+
+        let exe = std::env::current_exe().unwrap();
+        let by_exe: Filter = format!("hash={}", util::hash_string(exe.to_string_lossy()))
+            .parse()
+            .unwrap();
+        let by_comm: Filter = format!("hash={}", util::hash_string(process.get_command().unwrap()))
+            .parse()
+            .unwrap();
+
+        let subject = ProcessSubject {
+            process: &process,
+            identity: &Identity::Exe,
+        };
+        assert!(by_exe.matches(&subject));
+        assert!(!by_comm.matches(&subject));
+
+        let subject = ProcessSubject {
+            process: &process,
+            identity: &Identity::Comm,
+        };
+        assert!(!by_exe.matches(&subject));
+        assert!(by_comm.matches(&subject));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error(""), "Unexpected end of filter expression");
+        assert_eq!(error("comm="), "Unexpected end of filter expression");
+        assert_eq!(
+            error("comm=firefox and"),
+            "Unexpected end of filter expression"
+        );
+        assert_eq!(
+            error("path='/opt/My Apps"),
+            "Unexpected end of filter expression"
+        );
+        assert_eq!(error("comm=firefox )"), "Unexpected ')' at position 14");
+        assert_eq!(
+            error("(comm=firefox"),
+            "Unexpected end of filter expression"
+        );
+        assert_eq!(error("comm firefox"), "Unexpected 'firefox' at position 6");
+        assert_eq!(error("comm=a comm=b"), "Unexpected 'comm=b' at position 8");
+        assert_eq!(error("name=firefox"), "Unknown filter field 'name'");
+        assert_eq!(
+            error("size~=1"),
+            "Operator '~=' is not supported for 'size'"
+        );
+        assert_eq!(
+            error("comm<firefox"),
+            "Operator '<' is not supported for 'comm'"
+        );
+        assert_eq!(
+            error("size>10X"),
+            "Invalid value '10X' for 'size': expected a size like 100M"
+        );
+        assert_eq!(
+            error("mtime>soon"),
+            "Invalid value 'soon' for 'mtime': expected an age like 12h or 7d"
+        );
+        assert!(error("comm~=(").starts_with("Invalid value '(' for 'comm': "));
+    }
+}
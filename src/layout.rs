@@ -0,0 +1,190 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::snapshot::MappedFile;
+
+/// From linux/fs.h and linux/fiemap.h
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+const FIBMAP: libc::c_ulong = 1;
+const FIGETBSZ: libc::c_ulong = 2;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x0000_0002;
+
+#[repr(C)]
+#[derive(Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+/// A `struct fiemap` with room for a single extent
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; 1],
+}
+
+/// The order in which files are read when they are faulted in
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum IoOrder {
+    /// In the order of the work set, all files in parallel
+    #[default]
+    None,
+
+    /// By device and inode number, one file at a time per device
+    Inode,
+
+    /// By device and the physical location of the first block of the file,
+    /// falling back to the inode number, one file at a time per device
+    Physical,
+}
+
+impl FromStr for IoOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(IoOrder::None),
+            "inode" => Ok(IoOrder::Inode),
+            "physical" => Ok(IoOrder::Physical),
+            _ => Err(format!(
+                "Invalid I/O order '{}', expected one of: none, inode, physical",
+                s
+            )),
+        }
+    }
+}
+
+impl IoOrder {
+    pub fn get_name(self) -> &'static str {
+        match self {
+            IoOrder::None => "none",
+            IoOrder::Inode => "inode",
+            IoOrder::Physical => "physical",
+        }
+    }
+}
+
+/// Get the byte offset of the first block of a file on its device, with
+/// FIEMAP, or with FIBMAP which requires CAP_SYS_RAWIO. `None` for empty
+/// files and file systems that support neither, like tmpfs.
+pub fn get_physical_offset<P: AsRef<Path>>(path: P) -> Option<u64> {
+    let f = File::open(path.as_ref()).ok()?;
+
+    let mut fiemap = Fiemap {
+        fm_length: u64::MAX,
+        fm_extent_count: 1,
+        ..Default::default()
+    };
+
+    let result = unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_FIEMAP, &mut fiemap) };
+    if result == 0 {
+        let extent = &fiemap.fm_extents[0];
+
+        return if fiemap.fm_mapped_extents == 0 || extent.fe_flags & FIEMAP_EXTENT_UNKNOWN != 0 {
+            None
+        } else {
+            Some(extent.fe_physical)
+        };
+    }
+
+    let mut block_size: libc::c_int = 0;
+    let result = unsafe { libc::ioctl(f.as_raw_fd(), FIGETBSZ, &mut block_size) };
+    if result != 0 {
+        return None;
+    }
+
+    let mut block: libc::c_int = 0;
+    let result = unsafe { libc::ioctl(f.as_raw_fd(), FIBMAP, &mut block) };
+    if result != 0 || block == 0 {
+        return None;
+    }
+
+    Some(block as u64 * block_size as u64)
+}
+
+/// Sorts the files of a device: whether the physical location is unknown,
+/// the physical location, and the inode
+type SortKey = (bool, u64, u64);
+
+/// Group the files by device, and sort the files of each device in `order`.
+/// Files without a known physical location follow the others, by inode.
+/// With `IoOrder::None`, all files are returned in a single group in their
+/// original order.
+pub fn group_by_device(files: &[MappedFile], order: IoOrder) -> Vec<Vec<MappedFile>> {
+    if order == IoOrder::None {
+        return vec![files.to_vec()];
+    }
+
+    // missing files are gathered under device 0
+    let mut devices: BTreeMap<u64, Vec<(SortKey, MappedFile)>> = BTreeMap::new();
+
+    for file in files.iter() {
+        let (device, key) = match fs::metadata(&file.path) {
+            Ok(metadata) => {
+                let offset = match order {
+                    IoOrder::Physical => get_physical_offset(&file.path),
+                    _ => None,
+                };
+
+                (
+                    metadata.dev(),
+                    (offset.is_none(), offset.unwrap_or(0), metadata.ino()),
+                )
+            }
+
+            Err(_) => (0, (true, 0, 0)),
+        };
+
+        devices.entry(device).or_default().push((key, file.clone()));
+    }
+
+    devices
+        .into_values()
+        .map(|mut files| {
+            files.sort_by_key(|(key, _)| *key);
+            files.into_iter().map(|(_, file)| file).collect()
+        })
+        .collect()
+}
+
+/// Sort the files like `group_by_device`, one device after the other
+pub fn sort_files(files: &[MappedFile], order: IoOrder) -> Vec<MappedFile> {
+    group_by_device(files, order)
+        .into_iter()
+        .flatten()
+        .collect()
+}
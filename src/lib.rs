@@ -0,0 +1,72 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Pre-fault and lock files into the kernel's page cache.
+//!
+//! A [`Snapshot`] records the files that a [`Process`] has mapped, and the
+//! byte ranges of each [`MappedFile`]. Snapshots are kept in a
+//! [`SnapshotStore`], static lists of files are read with [`FileList`].
+//! The files of snapshots and file lists are faulted into the page cache
+//! with [`prefault_file_mappings`] by a pool of [`Workers`], locked with
+//! [`mlock_file_mappings`], and their page cache residency is queried with
+//! [`get_file_residency`]. Files that have been evicted again are found and
+//! faulted in with [`watch::rewarm`].
+//!
+//! Nothing in this crate writes to stdout or stderr. Errors are returned to
+//! the caller, as `failure::Error` values that wrap the error types of the
+//! modules, like [`SnapshotError`], or as [`MemoryError`] per file.
+
+pub(crate) mod elf;
+pub mod filelist;
+pub mod filter;
+pub(crate) mod layout;
+pub mod memory;
+pub(crate) mod pressure;
+pub mod process;
+pub(crate) mod procmon;
+pub(crate) mod registry;
+pub mod settings;
+pub mod snapshot;
+pub mod store;
+pub(crate) mod trace;
+pub(crate) mod util;
+pub mod watch;
+pub mod workers;
+pub(crate) mod workset;
+
+pub use crate::elf::ElfError;
+pub use crate::filelist::FileList;
+pub use crate::layout::{sort_files, IoOrder};
+pub use crate::memory::{
+    get_file_residency, mlock_file_mappings, munlock_regions, prefault_file_mappings,
+    prime_dentry_cache, Alignment, LockedRegion, MemoryError, Residency,
+};
+pub use crate::pressure::{
+    prefault_adaptive, AdaptiveReport, Governor, PressureError, PressureLimits,
+};
+pub use crate::process::{Process, ProcessError};
+pub use crate::procmon::{ProcEvent, ProcMonitor, ProcMonitorError};
+pub use crate::registry::{LockRegistry, RegionStatus, SourceStatus, Status, SyncReport};
+pub use crate::snapshot::{Identity, MappedFile, MappedRange, Snapshot, SnapshotError};
+pub use crate::store::SnapshotStore;
+pub use crate::trace::{Trace, TraceError, Tracer};
+pub use crate::util::format_file_size;
+pub use crate::workers::{IoLimits, Workers};
+pub use crate::workset::{Skipped, Source, WorkSet};
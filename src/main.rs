@@ -18,38 +18,52 @@
     along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use config;
-use ctrlc;
+#![allow(non_local_definitions)]
+
 use failure::{Error, Fail};
 use lazy_static::lazy_static;
-use libc;
-use std::env;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
-use walkdir;
-
-mod filelist;
-mod memory;
-mod process;
-mod snapshot;
-mod util;
 
-// use crate::memory::*;
-use crate::filelist::*;
-use crate::process::*;
-use crate::snapshot::*;
-// use crate::util::*;
+use prefault::filelist::*;
+use prefault::filter::*;
+use prefault::memory;
+use prefault::process::*;
+use prefault::settings::*;
+use prefault::snapshot::*;
+use prefault::store::*;
+use prefault::watch;
+use prefault::workers::*;
+use prefault::{
+    format_file_size, prefault_adaptive, sort_files, Governor, IoOrder, LockRegistry,
+    PressureLimits, Source, Status, Tracer, WorkSet,
+};
+
+mod daemon;
+mod output;
+
+use crate::daemon::*;
+use crate::output::*;
 
 lazy_static! {
     pub static ref RUNNING: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
 }
 
+/// Set on SIGHUP, asks `prefault mlock` to reload its configuration
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Pre-fault and optionally lock files into the kernel's page cache memory")]
 pub struct Options {
@@ -63,6 +77,14 @@ pub struct Options {
     )]
     verbosity: u8,
 
+    #[structopt(
+        long = "format",
+        default_value = "table",
+        possible_values = &["table", "json", "jsonl"],
+        help = "Write the results as text, as a JSON array or as one JSON object per line"
+    )]
+    format: Format,
+
     #[structopt(subcommand)]
     cmd: Command,
 }
@@ -90,6 +112,18 @@ enum Command {
         filter: Option<String>,
     },
 
+    #[structopt(
+        name = "priority",
+        about = "Set the priority of process snapshots for cache and mlock"
+    )]
+    Priority {
+        #[structopt(short = "f", long = "filter")]
+        filter: Option<String>,
+
+        #[structopt(help = "Snapshots with a higher priority are cached and locked first")]
+        priority: i32,
+    },
+
     #[structopt(name = "show", about = "Show information about process snapshots")]
     Show {
         #[structopt(short = "f", long = "filter")]
@@ -103,6 +137,52 @@ enum Command {
 
         #[structopt(short = "p")]
         pid: Option<libc::pid_t>,
+
+        #[structopt(
+            long = "residency",
+            help = "Record which pages of the mapped files are resident in the page cache"
+        )]
+        residency: bool,
+
+        #[structopt(
+            long = "elf",
+            parse(from_os_str),
+            help = "Build a snapshot from the shared library dependencies of an executable"
+        )]
+        elf: Option<PathBuf>,
+
+        #[structopt(
+            long = "unit",
+            help = "Take a single snapshot of all processes of a systemd unit"
+        )]
+        unit: Option<String>,
+
+        #[structopt(
+            long = "cgroup",
+            help = "Take a single snapshot of all processes in a cgroup"
+        )]
+        cgroup: Option<String>,
+
+        #[structopt(
+            long = "tree",
+            requires = "pid",
+            help = "Include all descendants of the process in the snapshot"
+        )]
+        tree: bool,
+
+        #[structopt(
+            long = "merge",
+            help = "Union the files into the existing snapshot instead of replacing it"
+        )]
+        merge: bool,
+
+        #[structopt(
+            long = "expire-after",
+            requires = "merge",
+            parse(try_from_str = parse_expire_after),
+            help = "Remove files that have not been seen in this many merged captures"
+        )]
+        expire_after: Option<u32>,
     },
 
     #[structopt(
@@ -117,11 +197,53 @@ enum Command {
         pid: Option<libc::pid_t>,
     },
 
-    //#[structopt(name = "trace", about = "Trace a process and record accessed files")]
-    //Trace {
-    //#[structopt(short = "c")]
-    //command: Option<String>,
-    //},
+    #[structopt(name = "trace", about = "Trace a process and record accessed files")]
+    Trace {
+        #[structopt(
+            short = "t",
+            long = "time",
+            help = "Stop recording after the specified number of seconds"
+        )]
+        time: Option<u64>,
+
+        #[structopt(last = true, required = true, help = "The command to trace")]
+        command: Vec<String>,
+    },
+
+    #[structopt(
+        name = "daemon",
+        about = "Monitor processes, take snapshots and prefault them on exec"
+    )]
+    Daemon {
+        #[structopt(short = "f", long = "filter")]
+        filter: Option<String>,
+
+        #[structopt(
+            short = "d",
+            long = "delay",
+            default_value = "10",
+            help = "Take snapshots after a process has been running for the specified number of seconds"
+        )]
+        delay: u64,
+    },
+
+    #[structopt(
+        name = "verify",
+        about = "Find missing or changed files in process snapshots and static file lists"
+    )]
+    Verify {
+        #[structopt(short = "f", long = "filter")]
+        filter: Option<String>,
+    },
+
+    #[structopt(
+        name = "repair",
+        about = "Re-resolve missing or changed files in process snapshots"
+    )]
+    Repair {
+        #[structopt(short = "f", long = "filter")]
+        filter: Option<String>,
+    },
 
     #[structopt(name = "remove", about = "Remove a process snapshot")]
     Remove {
@@ -133,6 +255,66 @@ enum Command {
     Cache {
         #[structopt(short = "f", long = "filter")]
         filter: Option<String>,
+
+        #[structopt(
+            long = "unit",
+            conflicts_with = "filter",
+            help = "Only fault in the snapshot of a systemd unit, e.g. from ExecStartPre="
+        )]
+        unit: Option<String>,
+
+        #[structopt(
+            long = "adaptive",
+            help = "Slow down and pause while the memory pressure is high"
+        )]
+        adaptive: bool,
+
+        #[structopt(
+            long = "max-pause",
+            requires = "adaptive",
+            help = "Give up after pausing for the specified number of seconds [default: 60]"
+        )]
+        max_pause: Option<u64>,
+
+        #[structopt(
+            long = "watch",
+            conflicts_with = "adaptive",
+            help = "Keep running, and fault in files again that have been evicted from the page cache"
+        )]
+        watch: bool,
+
+        #[structopt(
+            short = "a",
+            long = "align",
+            default_value = "page",
+            help = "Round mapped ranges out to page, hugepage or readahead boundaries"
+        )]
+        align: memory::Alignment,
+    },
+
+    #[structopt(
+        name = "benchmark",
+        about = "Compare the orders in which files are read when they are faulted in"
+    )]
+    Benchmark {
+        #[structopt(short = "f", long = "filter")]
+        filter: Option<String>,
+
+        #[structopt(
+            short = "n",
+            long = "runs",
+            default_value = "3",
+            help = "Fault in the files the specified number of times per order"
+        )]
+        runs: u32,
+
+        #[structopt(
+            short = "a",
+            long = "align",
+            default_value = "page",
+            help = "Round mapped ranges out to page, hugepage or readahead boundaries"
+        )]
+        align: memory::Alignment,
     },
 
     #[structopt(
@@ -142,17 +324,107 @@ enum Command {
     Mlock {
         #[structopt(short = "f", long = "filter")]
         filter: Option<String>,
+        #[structopt(
+            short = "a",
+            long = "align",
+            default_value = "page",
+            help = "Round mapped ranges out to page, hugepage or readahead boundaries"
+        )]
+        align: memory::Alignment,
+    },
+
+    #[structopt(
+        name = "status",
+        about = "Show which files are locked into memory by a running mlock command"
+    )]
+    Status,
+
+    #[structopt(name = "config", about = "Inspect the effective configuration")]
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
     },
 }
 
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    #[structopt(
+        name = "dump",
+        about = "Print the merged configuration, and where each value came from"
+    )]
+    Dump,
+}
+
+impl Command {
+    fn get_filter(&self) -> Option<&String> {
+        match self {
+            Command::List { filter }
+            | Command::Enable { filter }
+            | Command::Disable { filter }
+            | Command::Priority { filter, .. }
+            | Command::Show { filter }
+            | Command::Snapshot { filter, .. }
+            | Command::Incore { filter, .. }
+            | Command::Daemon { filter, .. }
+            | Command::Verify { filter }
+            | Command::Repair { filter }
+            | Command::Remove { filter }
+            | Command::Cache { filter, .. }
+            | Command::Benchmark { filter, .. }
+            | Command::Mlock { filter, .. } => filter.as_ref(),
+
+            Command::Trace { .. } | Command::Status | Command::Config { .. } => None,
+        }
+    }
+
+    /// Whether the command writes snapshots or looks them up by the name
+    /// of their file, which needs the names of the current version
+    fn uses_snapshot_names(&self) -> bool {
+        matches!(
+            self,
+            Command::Enable { .. }
+                | Command::Disable { .. }
+                | Command::Priority { .. }
+                | Command::Snapshot { .. }
+                | Command::Trace { .. }
+                | Command::Daemon { .. }
+                | Command::Repair { .. }
+                | Command::Remove { .. }
+        )
+    }
+
+    /// Whether the command keeps running until it is stopped
+    fn is_long_running(&self) -> bool {
+        match self {
+            Command::Daemon { .. } | Command::Mlock { .. } => true,
+            Command::Cache { watch, .. } => *watch,
+
+            _ => false,
+        }
+    }
+}
+
+/// A command failed
+const EXIT_FAILURE: i32 = 1;
+
+/// Invalid command line, filter expression or configuration
+const EXIT_USAGE: i32 = 2;
+
+/// `verify` found stale entries, or `repair` could not repair everything
+const EXIT_STALE: i32 = 3;
+
 #[derive(Fail, Debug)]
 #[fail(display = "An error occurred")]
 enum CommandError {
     #[fail(display = "Invalid command parameters: {}", _0)]
     InvalidParamaters(String),
 
-    // #[fail(display = "Invalid filter expression")]
-    // InvalidFilter,
+    #[fail(display = "Invalid filter expression: {}", _0)]
+    InvalidFilter(#[fail(cause)] FilterError),
+
+    #[fail(display = "Could not load configuration: {}", _0)]
+    InvalidConfiguration(#[fail(cause)] Error),
+
     #[fail(display = "Error during command execution: {}", _0)]
     ExecutionError(#[fail(cause)] Error),
 
@@ -160,14 +432,62 @@ enum CommandError {
     Process { msg: String },
 }
 
-fn do_list<T: AsRef<str>, P: AsRef<Path>>(
-    filter: Option<T>,
+fn get_exit_status(e: &Error) -> i32 {
+    match e.downcast_ref::<CommandError>() {
+        Some(CommandError::InvalidParamaters(_))
+        | Some(CommandError::InvalidFilter(_))
+        | Some(CommandError::InvalidConfiguration(_)) => EXIT_USAGE,
+
+        _ => EXIT_FAILURE,
+    }
+}
+
+/// The total size of the files that exist, missing files are reported
+fn get_total_size<'a, I: Iterator<Item = &'a PathBuf>>(files: I, output: &mut Output) -> u64 {
+    let mut result = 0;
+
+    for file in files {
+        match fs::metadata(file) {
+            Ok(metadata) => result += metadata.len(),
+
+            Err(e) => output.error(file.display(), e),
+        }
+    }
+
+    result
+}
+
+fn get_snapshot_record<P: AsRef<Path>>(
+    path: P,
+    snapshot: &Snapshot,
+    output: &mut Output,
+) -> SnapshotRecord {
+    SnapshotRecord {
+        hash: snapshot.get_hash(),
+        key: snapshot.get_key().to_string(),
+        command: snapshot.command.clone(),
+        path: path.as_ref().to_path_buf(),
+        enabled: snapshot.enabled,
+        priority: snapshot.priority,
+        files: snapshot.mappings.len(),
+        size: get_total_size(snapshot.mappings.keys(), output),
+        exe: snapshot.exe.clone(),
+        cmdline: snapshot.cmdline.clone(),
+        unit: snapshot.unit.clone(),
+        mappings: vec![],
+    }
+}
+
+fn do_list<P: AsRef<Path>>(
+    filter: Option<&Filter>,
     static_filelist_dir: P,
-    snapshot_dir: P,
-    opts: &Options,
+    store: &SnapshotStore,
+    output: &mut Output,
 ) -> Result<(), Error> {
     if filter.is_none() {
-        println!("{}:", static_filelist_dir.as_ref().display());
+        if output.is_table() {
+            println!("{}:", static_filelist_dir.as_ref().display());
+        }
 
         for entry in walkdir::WalkDir::new(static_filelist_dir.as_ref()) {
             let p = entry?;
@@ -180,222 +500,470 @@ fn do_list<T: AsRef<str>, P: AsRef<Path>>(
             let filelist =
                 FileList::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
 
-            let mut total_size = 0;
-            for file in filelist.files.iter() {
-                match fs::metadata(&file) {
-                    Ok(metadata) => {
-                        let size = metadata.len();
-                        total_size += size;
-                    }
-
-                    Err(e) => eprintln!("{}: {}", &file.display(), e),
-                }
-            }
-
-            println!(
-                "{} ({} files, {})",
-                p.file_name().to_string_lossy(),
-                filelist.files.len(),
-                util::format_file_size(total_size)
-            );
-            println!();
+            let size = get_total_size(filelist.files.iter(), output);
+
+            let record = Record::FileList {
+                path: p.path().to_path_buf(),
+                files: filelist.files.len(),
+                size,
+            };
+
+            output.emit(record, || {
+                println!(
+                    "{} ({} files, {})",
+                    p.file_name().to_string_lossy(),
+                    filelist.files.len(),
+                    format_file_size(size)
+                );
+                println!();
+            });
         }
     }
 
-    println!("{}:", snapshot_dir.as_ref().display());
+    // snapshots that are hidden by the user store are not listed
+    let paths = store.get_snapshot_paths()?;
 
-    for entry in walkdir::WalkDir::new(snapshot_dir.as_ref()) {
-        let p = entry?;
-        if p.file_type().is_dir() || !match_filter(filter.as_ref(), &p.path(), &opts) {
-            continue;
+    for dir in store.get_dirs() {
+        if output.is_table() {
+            println!("{}:", dir.display());
         }
 
-        let snapshot = Snapshot::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
+        for path in paths.iter().filter(|p| p.parent() == Some(dir)) {
+            let snapshot = Snapshot::new_from_file(path).map_err(CommandError::ExecutionError)?;
+            if !match_filter(filter, &snapshot) {
+                continue;
+            }
 
-        let mut total_size = 0;
-        for mapping in snapshot.mappings.iter() {
-            match fs::metadata(&mapping) {
-                Ok(metadata) => {
-                    let size = metadata.len();
-                    total_size += size;
-                }
+            let record = get_snapshot_record(path, &snapshot, output);
+            let text = format!(
+                "{} {} ({} files, {})",
+                record.hash,
+                record.key,
+                record.files,
+                format_file_size(record.size)
+            );
 
-                Err(e) => eprintln!("{}: {}", &mapping.display(), e),
-            }
+            output.emit(Record::Snapshot(record), || println!("{}", text));
         }
-
-        println!(
-            "{} {} ({} files, {})",
-            snapshot.get_hash(),
-            &snapshot.command,
-            snapshot.mappings.len(),
-            util::format_file_size(total_size)
-        );
     }
 
     Ok(())
 }
 
-fn do_set_state<T: AsRef<str>, P: AsRef<Path>>(
-    filter: Option<T>,
-    snapshot_dir: P,
+fn do_set_state(
+    filter: Option<&Filter>,
+    store: &SnapshotStore,
     enable: bool,
-    opts: &Options,
+    output: &mut Output,
 ) -> Result<(), Error> {
-    println!("{}:", snapshot_dir.as_ref().display());
+    if output.is_table() {
+        println!("{}:", store.get_writable_dir().display());
+    }
 
-    for entry in walkdir::WalkDir::new(snapshot_dir.as_ref()) {
-        let p = entry?;
-        if p.file_type().is_dir() || !match_filter(filter.as_ref(), &p.path(), &opts) {
+    for path in store.get_snapshot_paths()? {
+        let mut snapshot = Snapshot::new_from_file(&path).map_err(CommandError::ExecutionError)?;
+        if !match_filter(filter, &snapshot) {
             continue;
         }
 
-        let mut snapshot =
-            Snapshot::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
-
         snapshot.set_enabled(enable);
-        snapshot.save_to_file(snapshot_dir.as_ref())?;
+        let path = snapshot.save_to_file(store.get_writable_dir())?;
 
-        println!(
-            "{} ({} files) - Enabled: {}",
-            snapshot.command,
-            snapshot.mappings.len(),
-            enable
-        );
+        let record = get_snapshot_record(&path, &snapshot, output);
+
+        output.emit(Record::Snapshot(record), || {
+            println!(
+                "{} ({} files) - Enabled: {}",
+                snapshot.command,
+                snapshot.mappings.len(),
+                enable
+            )
+        });
     }
 
     Ok(())
 }
 
-fn do_show<T: AsRef<str>, P: AsRef<Path>>(
-    filter: Option<T>,
-    snapshot_dir: P,
-    opts: &Options,
+fn do_set_priority(
+    filter: Option<&Filter>,
+    store: &SnapshotStore,
+    priority: i32,
+    output: &mut Output,
 ) -> Result<(), Error> {
-    for entry in walkdir::WalkDir::new(snapshot_dir.as_ref()) {
-        let p = entry?;
-        if p.file_type().is_dir() || !match_filter(filter.as_ref(), &p.path(), &opts) {
+    if output.is_table() {
+        println!("{}:", store.get_writable_dir().display());
+    }
+
+    for path in store.get_snapshot_paths()? {
+        let mut snapshot = Snapshot::new_from_file(&path).map_err(CommandError::ExecutionError)?;
+        if !match_filter(filter, &snapshot) {
             continue;
         }
 
-        let snapshot = Snapshot::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
+        snapshot.set_priority(priority);
+        let path = snapshot.save_to_file(store.get_writable_dir())?;
 
-        println!(
-            "{} ({} files) - Enabled: {}",
-            snapshot.command,
-            snapshot.mappings.len(),
-            snapshot.enabled
+        let record = get_snapshot_record(&path, &snapshot, output);
+
+        output.emit(Record::Snapshot(record), || {
+            println!(
+                "{} ({} files) - Priority: {}",
+                snapshot.command,
+                snapshot.mappings.len(),
+                priority
+            )
+        });
+    }
+
+    Ok(())
+}
+
+fn format_snapshot_details(record: &SnapshotRecord) -> String {
+    let mut result = format!(
+        "{} ({} files) - Enabled: {} - Priority: {}\n",
+        record.key, record.files, record.enabled, record.priority
+    );
+
+    if let Some(exe) = &record.exe {
+        result += &format!("Executable: {}\n", exe.display());
+    }
+
+    if let Some(cmdline) = &record.cmdline {
+        result += &format!("Command line: {}\n", cmdline.join(" "));
+    }
+
+    if let Some(unit) = &record.unit {
+        result += &format!("Unit: {}\n", unit);
+    }
+
+    for file in record.mappings.iter() {
+        // only merged snapshots have more than a single hit
+        let hits = match file.hits {
+            Some(hits) if file.missed > 0 => format!(
+                ", {} hits, not seen in the last {} captures",
+                hits, file.missed
+            ),
+            Some(hits) if hits > 1 => format!(", {} hits", hits),
+            _ => String::new(),
+        };
+
+        result += &format!(
+            "\t{} ({}{})\n",
+            &file.path.display(),
+            format_file_size(file.size),
+            hits
         );
 
-        let mut total_size = 0;
-        for mapping in snapshot.mappings {
-            match fs::metadata(&mapping) {
-                Ok(metadata) => {
-                    let size = metadata.len();
-                    total_size += size;
+        if !file.processes.is_empty() {
+            result += &format!("\t\tUsed by: {}\n", file.processes.join(", "));
+        }
+    }
+
+    result += &format!("Total: {}\n", format_file_size(record.size));
 
-                    println!(
-                        "\t{} ({})",
-                        &mapping.display(),
-                        util::format_file_size(size)
-                    );
-                }
+    result
+}
+
+fn do_show(
+    filter: Option<&Filter>,
+    store: &SnapshotStore,
+    output: &mut Output,
+) -> Result<(), Error> {
+    for path in store.get_snapshot_paths()? {
+        let snapshot = Snapshot::new_from_file(&path).map_err(CommandError::ExecutionError)?;
+        if !match_filter(filter, &snapshot) {
+            continue;
+        }
 
-                Err(e) => eprintln!("{}: {}", &mapping.display(), e),
+        let mut record = get_snapshot_record(&path, &snapshot, output);
+
+        for (mapping, file) in snapshot.mappings.iter() {
+            // missing files have been reported by get_snapshot_record
+            if let Ok(metadata) = fs::metadata(mapping) {
+                record.mappings.push(FileRecord {
+                    path: mapping.clone(),
+                    size: metadata.len(),
+                    hits: file.hits,
+                    missed: file.missed,
+                    processes: file.processes.iter().cloned().collect(),
+                });
             }
         }
 
-        println!("Total: {}", util::format_file_size(total_size));
-        println!();
+        let text = format_snapshot_details(&record);
+
+        output.emit(Record::Snapshot(record), || println!("{}", text));
     }
 
     Ok(())
 }
 
-fn do_snapshot<T: AsRef<str>, P: AsRef<Path>>(
-    filter: Option<T>,
-    pid: Option<libc::pid_t>,
-    snapshot_dir: P,
-    opts: &Options,
-) -> Result<(), CommandError> {
-    if filter.is_none() && pid.is_none() {
-        return Err(CommandError::InvalidParamaters(
-            "Neither filter nor PID specified".into(),
-        ));
+/// Options of the snapshot command that apply to every snapshot taken
+struct SnapshotOptions {
+    residency: bool,
+    tree: bool,
+    merge: bool,
+    expire_after: Option<u32>,
+}
+
+/// Save a snapshot, or merge it into the stored snapshot with the same key
+fn save_snapshot(
+    mut snapshot: Snapshot,
+    store: &SnapshotStore,
+    options: &SnapshotOptions,
+    output: &mut Output,
+) -> Result<(), Error> {
+    if options.residency {
+        snapshot.record_residency();
     }
 
-    if let Some(pid) = pid {
-        match Process::new(pid) {
-            Ok(proc) => match Snapshot::new_from_process(&proc) {
-                Ok(snapshot) => {
-                    let path = snapshot
-                        .save_to_file(snapshot_dir.as_ref())
-                        .map_err(CommandError::ExecutionError)?;
+    let path = get_snapshot_path(store.get_writable_dir(), snapshot.get_key());
+
+    // a snapshot in the system store is merged into a copy in the user store
+    if let Some(existing_path) = store.find(snapshot.get_key()).filter(|_| options.merge) {
+        let mut existing = Snapshot::new_from_file(&existing_path)?;
+        existing.merge(snapshot, options.expire_after);
+
+        existing.save_to_file(store.get_writable_dir())?;
+
+        let record = Record::Saved {
+            path: path.clone(),
+            key: existing.get_key().to_string(),
+            files: existing.mappings.len(),
+            merged: true,
+        };
+
+        output.emit(record, || {
+            println!(
+                "Merged into {} ({} files)",
+                &path.display(),
+                existing.mappings.len()
+            )
+        });
+    } else {
+        snapshot.save_to_file(store.get_writable_dir())?;
+
+        let record = Record::Saved {
+            path: path.clone(),
+            key: snapshot.get_key().to_string(),
+            files: snapshot.mappings.len(),
+            merged: false,
+        };
+
+        output.emit(record, || {
+            println!(
+                "Wrote {} ({} files)",
+                &path.display(),
+                snapshot.mappings.len()
+            )
+        });
+    }
+
+    Ok(())
+}
+
+/// What the snapshot command takes snapshots of
+enum SnapshotTarget<'a> {
+    Elf(&'a PathBuf),
+    Pid(libc::pid_t),
+    Unit(&'a str),
+    Cgroup(&'a str),
+    Filter(&'a Filter),
+}
+
+impl<'a> SnapshotTarget<'a> {
+    fn new(
+        filter: Option<&'a Filter>,
+        pid: Option<libc::pid_t>,
+        elf: Option<&'a PathBuf>,
+        unit: Option<&'a String>,
+        cgroup: Option<&'a String>,
+    ) -> Result<Self, CommandError> {
+        match (elf, pid, unit, cgroup, filter) {
+            (Some(elf), _, _, _, _) => Ok(SnapshotTarget::Elf(elf)),
+            (_, Some(pid), _, _, _) => Ok(SnapshotTarget::Pid(pid)),
+            (_, _, Some(unit), _, _) => Ok(SnapshotTarget::Unit(unit)),
+            (_, _, _, Some(cgroup), _) => Ok(SnapshotTarget::Cgroup(cgroup)),
+            (_, _, _, _, Some(filter)) => Ok(SnapshotTarget::Filter(filter)),
+
+            _ => Err(CommandError::InvalidParamaters(
+                "Neither filter, PID, unit, cgroup nor ELF executable specified".into(),
+            )),
+        }
+    }
+}
+
+/// Take a single snapshot of all processes in a cgroup, stored under `key`
+fn snapshot_cgroup(cgroup: &str, key: &str, identity: &Identity) -> Result<Snapshot, Error> {
+    let processes = get_cgroup_processes(cgroup)?;
+
+    let mut snapshot =
+        Snapshot::new_from_processes(&processes.iter().collect::<Vec<&Process>>(), identity)?;
+
+    snapshot.cgroup = Some(cgroup.to_string());
+    snapshot.set_key(key);
+
+    Ok(snapshot)
+}
+
+fn do_snapshot(
+    target: SnapshotTarget,
+    options: &SnapshotOptions,
+    store: &SnapshotStore,
+    identity: &Identity,
+    output: &mut Output,
+) -> Result<(), CommandError> {
+    match target {
+        SnapshotTarget::Elf(elf) => {
+            let (snapshot, unresolved) =
+                Snapshot::new_from_elf(elf, identity).map_err(CommandError::ExecutionError)?;
+
+            for name in unresolved.iter() {
+                output.error(elf.display(), format!("Could not resolve {}", name));
+            }
+
+            save_snapshot(snapshot, store, options, output)
+                .map_err(CommandError::ExecutionError)?;
+        }
 
-                    println!("Wrote {}", &path.display());
+        SnapshotTarget::Pid(pid) => match Process::new(pid) {
+            Ok(proc) => {
+                let snapshot = if options.tree {
+                    Snapshot::new_from_process_tree(&proc, identity)
+                } else {
+                    Snapshot::new_from_process(&proc, identity)
                 }
+                .map_err(CommandError::ExecutionError)?;
 
-                Err(e) => return Err(CommandError::ExecutionError(e)),
-            },
+                save_snapshot(snapshot, store, options, output)
+                    .map_err(CommandError::ExecutionError)?;
+            }
 
             Err(e) => {
                 return Err(CommandError::Process {
                     msg: format!("{}", e),
                 })
             }
+        },
+
+        SnapshotTarget::Unit(unit) => {
+            let cgroup = find_unit_cgroup(unit).map_err(CommandError::ExecutionError)?;
+
+            let mut snapshot =
+                snapshot_cgroup(&cgroup, unit, identity).map_err(CommandError::ExecutionError)?;
+            snapshot.unit = Some(unit.to_string());
+
+            save_snapshot(snapshot, store, options, output)
+                .map_err(CommandError::ExecutionError)?;
         }
-    } else if let Some(filter) = filter {
-        for process in Process::enumerate().map_err(CommandError::ExecutionError)? {
-            if !match_filter_process(Some(filter.as_ref()), &process, &opts) {
-                continue;
-            }
 
-            match Snapshot::new_from_process(&process).map_err(CommandError::ExecutionError) {
-                Ok(snapshot) => {
-                    let path = snapshot
-                        .save_to_file(snapshot_dir.as_ref())
+        SnapshotTarget::Cgroup(cgroup) => {
+            let snapshot =
+                snapshot_cgroup(cgroup, cgroup, identity).map_err(CommandError::ExecutionError)?;
+
+            save_snapshot(snapshot, store, options, output)
+                .map_err(CommandError::ExecutionError)?;
+        }
+
+        SnapshotTarget::Filter(filter) => {
+            // when merging, processes that share a key form a single capture
+            let mut captured: BTreeMap<String, Snapshot> = BTreeMap::new();
+
+            for process in Process::enumerate().map_err(CommandError::ExecutionError)? {
+                let subject = ProcessSubject {
+                    process: &process,
+                    identity,
+                };
+
+                if !filter.matches(&subject) {
+                    continue;
+                }
+
+                // the process may have exited since it has been enumerated
+                let snapshot = match Snapshot::new_from_process(&process, identity) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        output.error(process.pid, e);
+                        continue;
+                    }
+                };
+
+                if !options.merge {
+                    save_snapshot(snapshot, store, options, output)
                         .map_err(CommandError::ExecutionError)?;
 
-                    println!("Wrote {}", &path.display());
+                    continue;
                 }
 
-                Err(e) => return Err(CommandError::ExecutionError(e.into())),
+                match captured.get_mut(snapshot.get_key()) {
+                    Some(existing) => existing.add(snapshot),
+
+                    None => {
+                        captured.insert(snapshot.get_key().to_string(), snapshot);
+                    }
+                }
+            }
+
+            for (_, snapshot) in captured.into_iter() {
+                save_snapshot(snapshot, store, options, output)
+                    .map_err(CommandError::ExecutionError)?;
             }
         }
-    } else {
-        return Err(CommandError::InvalidParamaters(
-            "Neither filter nor PID specified".into(),
-        ));
     }
 
     Ok(())
 }
 
-fn do_incore<T: AsRef<str>, P: AsRef<Path>>(
-    filter: Option<T>,
-    pid: Option<libc::pid_t>,
-    snapshot_dir: P,
-    opts: &Options,
-) -> Result<(), Error> {
-    if filter.is_none() && pid.is_none() {
-        return Err(
-            CommandError::InvalidParamaters("Neither filter nor PID specified".into()).into(),
-        );
+/// Report how much of each file is resident in the page cache
+fn report_residency(source: &str, paths: &[PathBuf], output: &mut Output) {
+    for path in paths.iter() {
+        match memory::get_file_residency(path) {
+            Ok(residency) => {
+                let percent = residency.get_percentage();
+
+                let record = Record::Residency {
+                    source: source.to_string(),
+                    path: path.clone(),
+                    size: residency.size,
+                    resident_pages: residency.resident_pages,
+                    percent,
+                };
+
+                output.emit(record, || {
+                    println!(
+                        "{:3}% {:5} {} ({})",
+                        percent,
+                        residency.resident_pages,
+                        path.display(),
+                        format_file_size(residency.size),
+                    )
+                });
+            }
+
+            Err(e) => output.error(path.display(), e),
+        }
     }
+}
 
+fn do_incore(
+    filter: Option<&Filter>,
+    pid: Option<libc::pid_t>,
+    store: &SnapshotStore,
+    identity: &Identity,
+    output: &mut Output,
+) -> Result<(), Error> {
     if let Some(pid) = pid {
         match Process::new(pid) {
             Ok(proc) => {
-                println!("{} mappings:", proc.get_command()?);
-                match Snapshot::new_from_process(&proc) {
-                    Ok(snapshot) => {
-                        let paths: Vec<PathBuf> = snapshot.mappings.iter().cloned().collect();
-                        memory::print_fincore(&paths)
-                            .map_err(|e| CommandError::ExecutionError(e.into()))?;
-                    }
-
-                    Err(e) => return Err(CommandError::ExecutionError(e).into()),
+                let command = proc.get_command()?;
+                if output.is_table() {
+                    println!("{} mappings:", command);
                 }
+
+                let snapshot = Snapshot::new_from_process(&proc, identity)
+                    .map_err(CommandError::ExecutionError)?;
+
+                report_residency(&command, &snapshot.get_paths(), output);
             }
 
             Err(e) => {
@@ -406,21 +974,13 @@ fn do_incore<T: AsRef<str>, P: AsRef<Path>>(
             }
         }
     } else if let Some(filter) = filter {
-        for entry in walkdir::WalkDir::new(snapshot_dir.as_ref()) {
-            let p = entry?;
-            if p.file_type().is_dir() || !match_filter(Some(filter.as_ref()), &p.path(), &opts) {
+        for path in store.get_snapshot_paths()? {
+            let snapshot = Snapshot::new_from_file(&path).map_err(CommandError::ExecutionError)?;
+            if !filter.matches(&snapshot) {
                 continue;
             }
 
-            match Snapshot::new_from_file(p.path()).map_err(CommandError::ExecutionError) {
-                Ok(snapshot) => {
-                    let paths: Vec<PathBuf> = snapshot.mappings.iter().cloned().collect();
-                    memory::print_fincore(&paths)
-                        .map_err(|e| CommandError::ExecutionError(e.into()))?;
-                }
-
-                Err(e) => return Err(CommandError::ExecutionError(e.into()).into()),
-            }
+            report_residency(snapshot.get_key(), &snapshot.get_paths(), output);
         }
     } else {
         return Err(
@@ -431,81 +991,358 @@ fn do_incore<T: AsRef<str>, P: AsRef<Path>>(
     Ok(())
 }
 
-fn do_remove<T: AsRef<str>, P: AsRef<Path>>(
-    filter: Option<T>,
-    snapshot_dir: P,
+fn do_trace<T: AsRef<str>>(
+    command: &[T],
+    time: Option<u64>,
+    store: &SnapshotStore,
+    identity: &Identity,
     opts: &Options,
+    output: &mut Output,
 ) -> Result<(), Error> {
-    // Err(CommandError::InvalidFilter)
+    let mut tracer = Tracer::spawn(command).map_err(CommandError::ExecutionError)?;
 
-    for entry in walkdir::WalkDir::new(snapshot_dir.as_ref()) {
-        let p = entry?;
-        if p.file_type().is_dir() || !match_filter(filter.as_ref(), &p.path(), &opts) {
-            continue;
-        }
+    let trace = tracer
+        .record(time.map(Duration::from_secs), &RUNNING)
+        .map_err(CommandError::ExecutionError)?;
 
-        if p.path().extension().unwrap_or_else(|| OsStr::new("")) == "snapshot" {
-            println!("Removing {}", p.path().display());
-            fs::remove_file(p.path()).map_err(|e| CommandError::ExecutionError(e.into()))?;
+    if opts.verbosity > 0 && output.is_table() {
+        for file in trace.files.iter() {
+            println!("{}", file.display());
         }
     }
 
+    let snapshot = Snapshot::new_from_trace(trace, identity);
+    let path = snapshot
+        .save_to_file(store.get_writable_dir())
+        .map_err(CommandError::ExecutionError)?;
+
+    let record = Record::Saved {
+        path: path.clone(),
+        key: snapshot.get_key().to_string(),
+        files: snapshot.mappings.len(),
+        merged: false,
+    };
+
+    output.emit(record, || {
+        println!(
+            "Wrote {} ({} files)",
+            &path.display(),
+            snapshot.mappings.len()
+        )
+    });
+
+    tracer.release();
+
     Ok(())
 }
 
-fn do_cache<T: AsRef<str>, P: AsRef<Path>>(
-    filter: Option<T>,
-    static_filelist_dir: P,
-    snapshot_dir: P,
+fn do_daemon(
+    filter: Option<&Filter>,
+    delay: u64,
+    store: &SnapshotStore,
+    settings: &Settings,
     opts: &Options,
 ) -> Result<(), Error> {
-    for entry in walkdir::WalkDir::new(static_filelist_dir.as_ref()) {
-        let p = entry?;
-        if p.file_type().is_dir()
-            || p.path().extension().unwrap_or_else(|| OsStr::new("")) != "list"
-        {
-            continue;
-        }
+    let workers = Workers::new(&settings.io_limits).map_err(CommandError::ExecutionError)?;
+
+    let mut daemon = Daemon::new(
+        store.clone(),
+        Duration::from_secs(delay),
+        settings.identity.clone(),
+        workers,
+        opts.verbosity,
+    )
+    .map_err(CommandError::ExecutionError)?;
+
+    daemon
+        .run(|process| {
+            let subject = ProcessSubject {
+                process,
+                identity: &settings.identity,
+            };
+
+            filter.map(|f| f.matches(&subject)).unwrap_or(false)
+        })
+        .map_err(CommandError::ExecutionError)?;
 
-        let filelist = FileList::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
+    Ok(())
+}
+
+fn report_stale_files(name: &str, stale: &[(PathBuf, FileState)], output: &mut Output) {
+    if output.is_table() {
+        println!("{} ({} stale files)", name, stale.len());
+    }
 
-        let files: Vec<PathBuf> = filelist.files.iter().cloned().collect();
+    for (path, state) in stale.iter() {
+        let record = Record::Stale {
+            source: name.to_string(),
+            path: path.clone(),
+            state: state.to_string(),
+        };
 
-        memory::prime_dentry_cache(&files);
-        memory::prefault_file_mappings(&files)
-            .map_err(|e| CommandError::ExecutionError(e.into()))?;
+        output.emit(record, || println!("\t{}: {}", state, path.display()));
     }
+}
 
-    for entry in walkdir::WalkDir::new(snapshot_dir.as_ref()) {
-        let p = entry?;
-        if p.file_type().is_dir() || !match_filter(filter.as_ref(), &p.path(), &opts) {
-            continue;
-        }
+fn get_stale_filelist_entries(filelist: &FileList) -> Vec<(PathBuf, FileState)> {
+    let mut result: Vec<(PathBuf, FileState)> = filelist
+        .files
+        .iter()
+        .filter(|f| fs::metadata(f).is_err())
+        .map(|f| (f.clone(), FileState::Missing))
+        .collect();
 
-        let snapshot = Snapshot::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    result
+}
 
-        if snapshot.enabled {
-            if opts.verbosity > 0 {
-                println!("{}", snapshot.command);
+/// Returns the number of stale entries found
+fn do_verify<P: AsRef<Path>>(
+    filter: Option<&Filter>,
+    static_filelist_dir: P,
+    store: &SnapshotStore,
+    output: &mut Output,
+) -> Result<usize, Error> {
+    let mut result = 0;
+
+    if filter.is_none() {
+        for entry in walkdir::WalkDir::new(static_filelist_dir.as_ref()) {
+            let p = entry?;
+            if p.file_type().is_dir()
+                || p.path().extension().unwrap_or_else(|| OsStr::new("")) != "list"
+            {
+                continue;
+            }
+
+            let filelist =
+                FileList::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
+
+            let stale = get_stale_filelist_entries(&filelist);
+            if !stale.is_empty() {
+                report_stale_files(&p.path().to_string_lossy(), &stale, output);
+                result += stale.len();
             }
+        }
+    }
 
-            let files: Vec<PathBuf> = snapshot.mappings.iter().cloned().collect();
+    for path in store.get_snapshot_paths()? {
+        let snapshot = Snapshot::new_from_file(&path).map_err(CommandError::ExecutionError)?;
+        if !match_filter(filter, &snapshot) {
+            continue;
+        }
 
-            memory::prime_dentry_cache(&files);
-            memory::prefault_file_mappings(&files)
-                .map_err(|e| CommandError::ExecutionError(e.into()))?;
+        let stale = snapshot.get_stale_files();
+        if !stale.is_empty() {
+            report_stale_files(
+                &format!("{} {}", snapshot.get_hash(), snapshot.command),
+                &stale,
+                output,
+            );
+            result += stale.len();
         }
     }
 
-    Ok(())
+    Ok(result)
 }
 
-fn do_mlock<T: AsRef<str>, P: AsRef<Path>>(
-    filter: Option<T>,
+/// Returns the number of snapshots and static file lists that could not be repaired
+fn do_repair<P: AsRef<Path>>(
+    filter: Option<&Filter>,
     static_filelist_dir: P,
-    snapshot_dir: P,
-    opts: &Options,
+    store: &SnapshotStore,
+    identity: &Identity,
+    output: &mut Output,
+) -> Result<usize, Error> {
+    let mut result = 0;
+
+    if filter.is_none() {
+        for entry in walkdir::WalkDir::new(static_filelist_dir.as_ref()) {
+            let p = entry?;
+            if p.file_type().is_dir()
+                || p.path().extension().unwrap_or_else(|| OsStr::new("")) != "list"
+            {
+                continue;
+            }
+
+            let filelist =
+                FileList::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
+
+            let stale = get_stale_filelist_entries(&filelist);
+            if !stale.is_empty() {
+                report_stale_files(&p.path().to_string_lossy(), &stale, output);
+                output.error(
+                    p.path().display(),
+                    "Static file lists have to be repaired manually",
+                );
+
+                result += 1;
+            }
+        }
+    }
+
+    for path in store.get_snapshot_paths()? {
+        let mut snapshot = Snapshot::new_from_file(&path).map_err(CommandError::ExecutionError)?;
+        if !match_filter(filter, &snapshot) {
+            continue;
+        }
+
+        let stale = snapshot.get_stale_files();
+        if stale.is_empty() {
+            continue;
+        }
+
+        report_stale_files(
+            &format!("{} {}", snapshot.get_hash(), snapshot.command),
+            &stale,
+            output,
+        );
+
+        let running = Process::enumerate()
+            .map_err(CommandError::ExecutionError)?
+            .find(|p| {
+                identity
+                    .get_process_key(p)
+                    .map(|key| key == snapshot.get_key())
+                    .unwrap_or(false)
+            });
+
+        if let Some(process) = running {
+            let mut repaired = Snapshot::new_from_process(&process, identity)
+                .map_err(CommandError::ExecutionError)?;
+            repaired.set_enabled(snapshot.enabled);
+            repaired.set_priority(snapshot.priority);
+
+            let new_path = repaired
+                .save_to_file(store.get_writable_dir())
+                .map_err(CommandError::ExecutionError)?;
+
+            if new_path != path && path.parent() == Some(store.get_writable_dir()) {
+                fs::remove_file(&path).map_err(|e| CommandError::ExecutionError(e.into()))?;
+            }
+
+            let record = Record::Saved {
+                path: new_path.clone(),
+                key: repaired.get_key().to_string(),
+                files: repaired.mappings.len(),
+                merged: false,
+            };
+
+            output.emit(record, || {
+                println!(
+                    "Wrote {} (from running process {})",
+                    new_path.display(),
+                    process.pid
+                )
+            });
+        } else {
+            match snapshot.repair_from_elf() {
+                Ok(unresolved) => {
+                    let new_path = snapshot
+                        .save_to_file(store.get_writable_dir())
+                        .map_err(CommandError::ExecutionError)?;
+
+                    if new_path != path && path.parent() == Some(store.get_writable_dir()) {
+                        fs::remove_file(&path)
+                            .map_err(|e| CommandError::ExecutionError(e.into()))?;
+                    }
+
+                    let record = Record::Saved {
+                        path: new_path.clone(),
+                        key: snapshot.get_key().to_string(),
+                        files: snapshot.mappings.len(),
+                        merged: false,
+                    };
+
+                    output.emit(record, || {
+                        println!("Wrote {} (from ELF dependencies)", new_path.display())
+                    });
+
+                    if !unresolved.is_empty() {
+                        for name in unresolved.iter() {
+                            output.error(&snapshot.command, format!("Could not resolve {}", name));
+                        }
+
+                        result += 1;
+                    }
+                }
+
+                Err(e) => {
+                    output.error(&snapshot.command, e);
+                    result += 1;
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn do_remove(
+    filter: Option<&Filter>,
+    store: &SnapshotStore,
+    output: &mut Output,
 ) -> Result<(), Error> {
+    for path in store.get_snapshot_paths()? {
+        if filter.is_some() {
+            let snapshot = Snapshot::new_from_file(&path).map_err(CommandError::ExecutionError)?;
+
+            if !match_filter(filter, &snapshot) {
+                continue;
+            }
+        }
+
+        if output.is_table() {
+            println!("Removing {}", path.display());
+        }
+
+        fs::remove_file(&path).map_err(|e| CommandError::ExecutionError(e.into()))?;
+
+        output.emit(Record::Removed { path }, || {});
+    }
+
+    Ok(())
+}
+
+/// Create the writable snapshot directory, and rename the snapshots in it
+/// that are still named after the hash of an earlier version
+fn do_migrate(store: &SnapshotStore, output: &mut Output) -> Result<(), Error> {
+    // unprivileged users only write to their own store
+    fs::create_dir_all(store.get_writable_dir())
+        .map_err(|e| CommandError::ExecutionError(e.into()))?;
+
+    let (renamed, failed) = match migrate_snapshots(store.get_writable_dir()) {
+        Ok(result) => result,
+
+        Err(e) => {
+            output.error("Could not migrate snapshots", e);
+            return Ok(());
+        }
+    };
+
+    for (path, e) in failed {
+        output.error(path.display(), e);
+    }
+
+    if output.is_table() && !renamed.is_empty() {
+        println!("Renamed {} snapshots to stable file names", renamed.len());
+    }
+
+    for (path, new_path) in renamed {
+        output.emit(Record::Renamed { path, new_path }, || {});
+    }
+
+    Ok(())
+}
+
+/// Collect the static file lists and enabled snapshots that should be
+/// cached or locked
+fn get_sources<P: AsRef<Path>>(
+    filter: Option<&Filter>,
+    static_filelist_dir: P,
+    store: &SnapshotStore,
+) -> Result<Vec<Source>, Error> {
+    let mut result = vec![];
+
     for entry in walkdir::WalkDir::new(static_filelist_dir.as_ref()) {
         let p = entry?;
         if p.file_type().is_dir()
@@ -516,221 +1353,1049 @@ fn do_mlock<T: AsRef<str>, P: AsRef<Path>>(
 
         let filelist = FileList::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
 
-        let files: Vec<PathBuf> = filelist.files.iter().cloned().collect();
-
-        // memory::prime_dentry_cache(&files);
-        memory::mlock_file_mappings(&files, opts)
-            .map_err(|e| CommandError::ExecutionError(e.into()))?;
+        result.push(Source {
+            path: p.path().to_path_buf(),
+            name: p.file_name().to_string_lossy().to_string(),
+            priority: filelist.priority,
+            files: filelist.get_mapped_files(),
+        });
     }
 
-    for entry in walkdir::WalkDir::new(snapshot_dir.as_ref()) {
-        let p = entry?;
-        if p.file_type().is_dir() || !match_filter(filter.as_ref(), &p.path(), &opts) {
+    for path in store.get_snapshot_paths()? {
+        let snapshot = Snapshot::new_from_file(&path).map_err(CommandError::ExecutionError)?;
+        if !match_filter(filter, &snapshot) {
             continue;
         }
 
-        let snapshot = Snapshot::new_from_file(p.path()).map_err(CommandError::ExecutionError)?;
-
         if snapshot.enabled {
-            if opts.verbosity > 0 {
-                println!("{}", snapshot.command);
-            }
+            result.push(get_snapshot_source(&path, &snapshot));
+        }
+    }
+
+    Ok(result)
+}
+
+fn get_snapshot_source<P: AsRef<Path>>(path: P, snapshot: &Snapshot) -> Source {
+    Source {
+        path: path.as_ref().to_path_buf(),
+        name: snapshot.get_key().to_string(),
+        priority: snapshot.priority,
+        files: snapshot.get_files(),
+    }
+}
+
+/// The snapshot taken with `snapshot --unit`, if it is enabled
+fn get_unit_sources(unit: &str, store: &SnapshotStore) -> Result<Vec<Source>, Error> {
+    let path = store.find(unit).ok_or_else(|| {
+        CommandError::InvalidParamaters(format!("There is no snapshot of {}", unit))
+    })?;
+
+    let snapshot = Snapshot::new_from_file(&path).map_err(CommandError::ExecutionError)?;
+    if !snapshot.enabled {
+        return Ok(vec![]);
+    }
+
+    Ok(vec![get_snapshot_source(&path, &snapshot)])
+}
+
+fn get_source_record<'a, I: Iterator<Item = &'a MappedFile>>(
+    name: &str,
+    path: &Path,
+    files: I,
+) -> SourceRecord {
+    let mut result = SourceRecord {
+        name: name.to_string(),
+        path: path.to_path_buf(),
+        files: 0,
+        size: 0,
+    };
+
+    for file in files {
+        result.files += 1;
+        result.size += fs::metadata(&file.path).map(|m| m.len()).unwrap_or(0);
+    }
+
+    result
+}
+
+fn report_workset(
+    command: &str,
+    locked: bool,
+    workset: &WorkSet,
+    budget: Option<u64>,
+    output: &mut Output,
+) {
+    let record = WorkSetRecord {
+        command: command.to_string(),
+        locked,
+        sources: workset
+            .sources
+            .iter()
+            .map(|s| get_source_record(&s.name, &s.path, s.files.iter()))
+            .collect(),
+        size: workset.size,
+        budget,
+        duplicates: workset.duplicates,
+        duplicate_size: workset.duplicate_size,
+        skipped: workset
+            .skipped
+            .iter()
+            .map(|s| SourceRecord {
+                name: s.name.clone(),
+                path: s.path.clone(),
+                files: s.files,
+                size: s.size,
+            })
+            .collect(),
+    };
+
+    output.emit(Record::WorkSet(record), || {
+        if workset.duplicates > 0 {
+            println!(
+                "Removed {} duplicate files ({})",
+                workset.duplicates,
+                format_file_size(workset.duplicate_size)
+            );
+        }
+
+        if let Some(budget) = budget {
+            println!(
+                "Memory budget: {} of {} used",
+                format_file_size(workset.size),
+                format_file_size(budget)
+            );
+        }
+
+        for skipped in workset.skipped.iter() {
+            eprintln!(
+                "{}: Skipped {} files ({}), the memory budget is exhausted",
+                skipped.name,
+                skipped.files,
+                format_file_size(skipped.size)
+            );
+        }
+    });
+}
+
+/// Fault the files of a work set into the page cache
+fn prefault_workset(
+    workset: &WorkSet,
+    align: memory::Alignment,
+    workers: &Workers,
+    output: &mut Output,
+) {
+    let paths = workset.get_paths();
+
+    if output.is_table() {
+        for path in paths.iter() {
+            println!("{}", path.display());
+        }
+    }
+
+    workers.install(|| memory::prime_dentry_cache(&paths));
+
+    for (path, e) in memory::prefault_file_mappings(&workset.get_files(), align, workers) {
+        output.error(path.display(), e);
+    }
+}
+
+/// Fault the files of a work set into the page cache, and back off while the
+/// memory pressure is high
+fn prefault_workset_adaptive(
+    workset: &WorkSet,
+    align: memory::Alignment,
+    limits: PressureLimits,
+    max_pause: Duration,
+    workers: &Workers,
+    output: &mut Output,
+) -> Result<(), Error> {
+    let mut governor = Governor::new(limits).map_err(CommandError::ExecutionError)?;
+
+    if !governor.has_psi() {
+        output.error(
+            "/proc/pressure/memory",
+            "Not available, only MemAvailable and cgroup memory events are taken into account",
+        );
+    }
 
-            let files: Vec<PathBuf> = snapshot.mappings.iter().cloned().collect();
+    let paths = workset.get_paths();
 
-            // memory::prime_dentry_cache(&files);
-            memory::mlock_file_mappings(&files, opts)
-                .map_err(|e| CommandError::ExecutionError(e.into()))?;
+    if output.is_table() {
+        for path in paths.iter() {
+            println!("{}", path.display());
         }
     }
 
+    workers.install(|| memory::prime_dentry_cache(&paths));
+
+    let files = sort_files(&workset.get_files(), workers.get_order());
+
+    let report = prefault_adaptive(&files, align, &mut governor, max_pause, workers, &RUNNING)
+        .map_err(CommandError::ExecutionError)?;
+
+    for (path, e) in report.errors.iter() {
+        output.error(path.display(), e);
+    }
+
+    let skipped_size = report
+        .skipped
+        .iter()
+        .map(|f| fs::metadata(&f.path).map(|m| m.len()).unwrap_or(0))
+        .sum();
+
+    let record = Record::Pressure {
+        throttled: report.throttled.as_secs_f64(),
+        paused: report.paused.as_secs_f64(),
+        skipped_files: report.skipped.len(),
+        skipped_size,
+    };
+
+    output.emit(record, || {
+        if report.throttled > Duration::from_secs(0) || report.paused > Duration::from_secs(0) {
+            println!(
+                "Throttled for {:.1}s and paused for {:.1}s because of memory pressure",
+                report.throttled.as_secs_f64(),
+                report.paused.as_secs_f64()
+            );
+        }
+
+        if !report.skipped.is_empty() {
+            let reason = if RUNNING.load(Ordering::SeqCst) {
+                format!(
+                    "the memory pressure did not fall within {}s",
+                    max_pause.as_secs()
+                )
+            } else {
+                "interrupted".to_string()
+            };
+
+            eprintln!(
+                "Skipped {} files ({}), {}",
+                report.skipped.len(),
+                format_file_size(skipped_size),
+                reason
+            );
+        }
+    });
+
     Ok(())
 }
 
-fn match_filter<T: AsRef<str>, P: AsRef<Path>>(
-    filter: Option<T>,
-    snapshot: P,
-    _opts: &Options,
-) -> bool {
-    // no filter matches all
-    if filter.is_none() {
-        return true;
+/// Options of the cache command
+struct CacheOptions {
+    align: memory::Alignment,
+
+    /// Adapt to the memory pressure, and give up after pausing for this long
+    adaptive: Option<Duration>,
+
+    /// Keep checking the residency of the files after faulting them in
+    watch: bool,
+}
+
+/// The snapshots and static file lists that `prefault cache` faults in
+fn get_cache_sources(
+    filter: Option<&Filter>,
+    unit: Option<&String>,
+    settings: &Settings,
+) -> Result<Vec<Source>, Error> {
+    match unit {
+        Some(unit) => get_unit_sources(unit, &settings.get_snapshot_store()),
+
+        None => get_sources(
+            filter,
+            &settings.static_filelist_dir,
+            &settings.get_snapshot_store(),
+        ),
     }
+}
+
+fn do_cache(
+    filter: Option<&Filter>,
+    unit: Option<&String>,
+    settings: &Settings,
+    options: &CacheOptions,
+    workers: &Workers,
+    opts: &Options,
+    output: &mut Output,
+) -> Result<(), Error> {
+    let align = options.align;
 
-    let filter = filter.unwrap();
-    let params: Vec<&str> = filter.as_ref().split('=').collect();
+    let sources = get_cache_sources(filter, unit, settings)?;
 
-    if params.len() != 2 {
-        panic!("WARNING: Invalid filter syntax: '{}'", &filter.as_ref());
-        // return false;
+    let budget = settings.max_cached_memory.map(|l| l.get_bytes());
+    let workset = WorkSet::new(sources, align, budget);
+
+    if opts.verbosity > 0 && output.is_table() {
+        for source in workset.sources.iter() {
+            println!("{} ({} files)", source.name, source.files.len());
+        }
+    }
+
+    match options.adaptive {
+        Some(max_pause) => prefault_workset_adaptive(
+            &workset,
+            align,
+            settings.pressure_limits,
+            max_pause,
+            workers,
+            output,
+        )?,
+
+        None => prefault_workset(&workset, align, workers, output),
     }
 
-    if params[0].starts_with("comm") {
-        match snapshot::Snapshot::new_from_file(snapshot.as_ref()) {
-            Ok(snapshot) => {
-                // TODO: Add support for regex
-                snapshot.command.contains(params[1].trim())
+    report_workset("cache", false, &workset, budget, output);
+
+    Ok(())
+}
+
+/// Check the residency of the files of the work set of `prefault cache`, and
+/// fault in the files that have been evicted again
+fn do_rewarm(
+    filter: Option<&Filter>,
+    unit: Option<&String>,
+    settings: &Settings,
+    align: memory::Alignment,
+    workers: &Workers,
+    opts: &Options,
+    output: &mut Output,
+) -> Result<(), Error> {
+    let sources = get_cache_sources(filter, unit, settings)?;
+
+    let budget = settings.max_cached_memory.map(|l| l.get_bytes());
+    let workset = WorkSet::new(sources, align, budget);
+
+    let report = watch::rewarm(&workset.get_files(), align, &settings.watch_limits, workers);
+
+    for (path, e) in report.errors.iter() {
+        output.error(path.display(), e);
+    }
+
+    let rewarmed_size = report.rewarmed.iter().map(|c| c.get_missing_size()).sum();
+    let deferred_size = report.deferred.iter().map(|c| c.get_missing_size()).sum();
+
+    let record = Record::Rewarm {
+        checked: report.checked,
+        rewarmed: report
+            .rewarmed
+            .iter()
+            .map(|c| c.file.path.clone())
+            .collect(),
+        rewarmed_size,
+        deferred: report.deferred.len(),
+        deferred_size,
+    };
+
+    output.emit(record, || {
+        if opts.verbosity > 0 {
+            for cold in report.rewarmed.iter() {
+                println!(
+                    "{} ({}% resident)",
+                    cold.file.path.display(),
+                    cold.residency.get_percentage()
+                );
             }
+        }
 
-            Err(e) => {
-                eprintln!("WARNING: Error matching filter: {}", e);
-                false
+        if !report.rewarmed.is_empty() {
+            println!(
+                "Faulted in {} of {} files again ({})",
+                report.rewarmed.len(),
+                report.checked,
+                format_file_size(rewarmed_size)
+            );
+        }
+
+        if !report.deferred.is_empty() {
+            println!(
+                "Deferred {} files ({}) to the next check, the I/O budget is used up",
+                report.deferred.len(),
+                format_file_size(deferred_size)
+            );
+        }
+    });
+
+    Ok(())
+}
+
+/// `benchmark` considers the reads complete when the number of resident
+/// pages did not grow for this long
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Wait until the ranges that have been faulted in are resident in the page
+/// cache, readahead(2) and madvise(2) only start reading them, and the
+/// kernel may not read all of a large file. Returns when the last page
+/// became resident.
+fn wait_until_resident(files: &[MappedFile], align: memory::Alignment) -> Instant {
+    let mut pending: Vec<&MappedFile> = files.iter().collect();
+
+    let mut resident_pages = 0;
+    let mut progress_at = Instant::now();
+
+    while !pending.is_empty() && progress_at.elapsed() < SETTLE_TIME {
+        let mut pages = 0;
+        let now = Instant::now();
+
+        pending.retain(|file| match memory::get_mapping_residency(file, align) {
+            Ok(residency) => {
+                pages += residency.resident_pages;
+                residency.resident_pages < residency.size.div_ceil(memory::get_page_size())
             }
+
+            Err(_) => false,
+        });
+
+        // files that became fully resident are not counted anymore
+        if pages != resident_pages {
+            resident_pages = pages;
+            progress_at = now;
         }
-    } else if params[0].starts_with("hash") {
-        match snapshot::Snapshot::new_from_file(snapshot.as_ref()) {
-            Ok(snapshot) => match params[1].parse::<u64>() {
-                Ok(hash) => snapshot.get_hash() == hash,
 
-                Err(e) => {
-                    panic!("Invalid hash value specified: {}", e);
-                    // return false;
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    progress_at
+}
+
+/// Fault in the files of the work set of `prefault cache` in every order,
+/// and evict them from the page cache before every run
+fn do_benchmark(
+    filter: Option<&Filter>,
+    settings: &Settings,
+    align: memory::Alignment,
+    runs: u32,
+    output: &mut Output,
+) -> Result<(), Error> {
+    if runs == 0 {
+        return Err(CommandError::InvalidParamaters("--runs has to be at least 1".into()).into());
+    }
+
+    let sources = get_cache_sources(filter, None, settings)?;
+
+    let budget = settings.max_cached_memory.map(|l| l.get_bytes());
+    let workset = WorkSet::new(sources, align, budget);
+    let files = workset.get_files();
+
+    if output.is_table() {
+        println!(
+            "Faulting in {} files ({}), {} runs per order",
+            files.len(),
+            format_file_size(workset.size),
+            runs
+        );
+    }
+
+    let mut first_run = true;
+
+    for order in [IoOrder::None, IoOrder::Inode, IoOrder::Physical].iter() {
+        let limits = IoLimits {
+            io_order: *order,
+            ..settings.io_limits
+        };
+        let workers = Workers::new(&limits).map_err(CommandError::ExecutionError)?;
+
+        let mut durations = vec![];
+
+        for _ in 0..runs {
+            if !RUNNING.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            for file in files.iter() {
+                // missing files are reported by prefault_file_mappings
+                let _ = memory::evict_file(&file.path);
+            }
+
+            let start = Instant::now();
+            let errors = memory::prefault_file_mappings(&files, align, &workers);
+            durations.push(wait_until_resident(&files, align) - start);
+
+            if first_run {
+                for (path, e) in errors.iter() {
+                    output.error(path.display(), e);
                 }
-            },
 
-            Err(e) => {
-                eprintln!("WARNING: Error matching filter: {}", e);
-                false
+                first_run = false;
             }
         }
-    } else {
-        false
+
+        let mean = durations.iter().sum::<Duration>() / runs;
+        let best = durations.iter().min().cloned().unwrap_or_default();
+        let rate = (workset.size as f64 / mean.as_secs_f64().max(f64::EPSILON)) as u64;
+
+        let record = Record::Benchmark {
+            order: order.get_name().to_string(),
+            runs,
+            files: files.len(),
+            size: workset.size,
+            mean: mean.as_secs_f64(),
+            best: best.as_secs_f64(),
+            rate,
+        };
+
+        output.emit(record, || {
+            println!(
+                "{:<10} mean {:>8.3}s  best {:>8.3}s  {}/s",
+                order.get_name(),
+                mean.as_secs_f64(),
+                best.as_secs_f64(),
+                format_file_size(rate)
+            );
+        });
     }
+
+    Ok(())
 }
 
-fn match_filter_process<T: AsRef<str>>(
-    filter: Option<T>,
-    process: &Process,
-    _opts: &Options,
-) -> bool {
-    // no filter matches all
-    if filter.is_none() {
-        return true;
+fn do_mlock(
+    filter: Option<&Filter>,
+    settings: &Settings,
+    registry: &mut LockRegistry,
+    align: memory::Alignment,
+    opts: &Options,
+    output: &mut Output,
+) -> Result<(), Error> {
+    let sources = get_sources(
+        filter,
+        &settings.static_filelist_dir,
+        &settings.get_snapshot_store(),
+    )?;
+
+    // files beyond max_locked_memory are skipped, files beyond
+    // RLIMIT_MEMLOCK are faulted into the page cache instead. Processes with
+    // CAP_IPC_LOCK are not limited by the latter
+    let max_locked = settings.max_locked_memory.map(|l| l.get_bytes());
+    let limit = memory::get_mlock_limit();
+    let budget = match (max_locked, limit) {
+        (Some(max_locked), Some(limit)) => Some(max_locked.min(limit)),
+        (max_locked, limit) => max_locked.or(limit),
+    };
+
+    let mut workset = WorkSet::new(sources, align, max_locked);
+
+    let leftover = match limit.filter(|limit| workset.size > *limit) {
+        Some(limit) => {
+            let leftover = workset.split_off(limit, align);
+
+            output.error(
+                "RLIMIT_MEMLOCK",
+                format!(
+                    "{} is too small to lock {}, faulting the remaining {} into the page cache instead",
+                    format_file_size(limit),
+                    format_file_size(workset.size + leftover.size),
+                    format_file_size(leftover.size)
+                ),
+            );
+
+            Some(leftover)
+        }
+
+        None => None,
+    };
+
+    let report = registry.sync(&workset);
+    let status = registry.get_status();
+
+    if opts.verbosity > 0 {
+        for name in report.unlocked.iter() {
+            println!("Unlocking {}", name);
+        }
+
+        for source in workset.sources.iter() {
+            if !report.locked.contains(&source.name) {
+                continue;
+            }
+
+            println!("Locking {}", source.name);
+
+            if opts.verbosity > 1 {
+                for file in source.files.iter() {
+                    println!("{}", file.path.display());
+                }
+            }
+        }
+    }
+
+    for (path, e) in report.errors {
+        output.error(path.display(), e);
+    }
+
+    if output.is_table() {
+        println!(
+            "Locked {} and unlocked {} snapshots and file lists ({} locked in total)",
+            report.locked.len(),
+            report.unlocked.len(),
+            format_file_size(status.get_locked_size())
+        );
     }
 
-    let filter = filter.unwrap();
-    let params: Vec<&str> = filter.as_ref().split('=').collect();
+    report_workset("mlock", true, &workset, budget, output);
+
+    if let Some(leftover) = leftover {
+        let workers = Workers::new(&settings.io_limits).map_err(CommandError::ExecutionError)?;
 
-    if params.len() != 2 {
-        panic!("WARNING: Invalid filter syntax: '{}'", &filter.as_ref());
-        // return false;
+        prefault_workset(&leftover, align, &workers, output);
+        report_workset("mlock", false, &leftover, None, output);
     }
 
-    params[0].starts_with("comm") && process.get_command().unwrap().starts_with(params[1].trim())
+    status
+        .save_to_file(&settings.status_file)
+        .map_err(CommandError::ExecutionError)?;
+
+    Ok(())
 }
 
-fn main() {
-    let r = RUNNING.clone();
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C handler");
+/// Read the amount of memory locked by a process, from the VmLck field of
+/// /proc/<pid>/status
+fn get_locked_memory(pid: libc::pid_t) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+
+    status
+        .lines()
+        .find(|l| l.starts_with("VmLck:"))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
 
-    let opts = Options::from_args();
-    let mut settings = config::Config::default();
-
-    settings
-        .merge(config::File::new(
-            opts.config_file
-                .clone()
-                .unwrap_or_else(|| "/etc/prefault/prefault.conf".into())
-                .to_str()
-                .unwrap(),
-            config::FileFormat::Toml,
-        ))
-        .expect("Could not read configuration file");
-
-    let home_dir = PathBuf::from(env::var("HOME").unwrap_or_else(|_| "/root".into()));
-    let mut snapshot_dir = settings
-        .get::<PathBuf>("snapshot_dir")
-        .unwrap_or_else(|_| PathBuf::from("/var/lib/prefault/snapshots"));
-
-    if snapshot_dir.starts_with("~/") {
-        snapshot_dir = home_dir.join(
-            snapshot_dir
-                .strip_prefix(Path::new("~/"))
-                .expect("Error building snapshot_dir"),
-        )
+fn do_config_dump(settings: &Settings, output: &mut Output) {
+    for (key, raw) in settings.values.iter() {
+        let known = Settings::is_known_key(key);
+
+        let source = if known {
+            raw.source.to_string()
+        } else {
+            format!("{}, unknown key, ignored", raw.source)
+        };
+
+        let record = Record::Setting {
+            key: key.clone(),
+            value: raw.value.clone(),
+            source: raw.source.to_string(),
+            known,
+        };
+
+        output.emit(record, || {
+            println!(
+                "{} = {} # {}",
+                key,
+                toml::Value::String(raw.value.clone()),
+                source
+            )
+        });
     }
+}
 
-    // println!("{}", &snapshot_dir.display());
-    fs::create_dir_all(&snapshot_dir).expect("Error creating snapshot directory");
+fn do_status<P: AsRef<Path>>(
+    status_file: P,
+    opts: &Options,
+    output: &mut Output,
+) -> Result<(), Error> {
+    let mut record = StatusRecord {
+        pid: None,
+        running: false,
+        sources: vec![],
+        locked_size: 0,
+        vmlck: None,
+    };
+
+    let status = match Status::new_from_file(status_file.as_ref()) {
+        Ok(status) => status,
+
+        Err(_) => {
+            output.emit(Record::Status(record), || {
+                println!("Nothing is locked into memory")
+            });
+
+            return Ok(());
+        }
+    };
 
-    let mut static_filelist_dir = settings
-        .get::<PathBuf>("static_filelist_dir")
-        .unwrap_or_else(|_| PathBuf::from("/etc/prefault/cache.d"));
+    record.pid = Some(status.pid);
 
-    if static_filelist_dir.starts_with("~/") {
-        static_filelist_dir = home_dir.join(
-            static_filelist_dir
-                .strip_prefix(Path::new("~/"))
-                .expect("Error building static_filelist_dir"),
-        )
+    let running = Process::new(status.pid)
+        .and_then(|p| p.get_command())
+        .map(|c| c == "prefault")
+        .unwrap_or(false);
+
+    if !running {
+        output.emit(Record::Status(record), || {
+            println!(
+                "Nothing is locked into memory (stale status file of PID {})",
+                status.pid
+            )
+        });
+
+        return Ok(());
     }
 
-    match opts.cmd {
-        Command::List { ref filter, .. } => {
-            do_list(filter.as_ref(), &static_filelist_dir, &snapshot_dir, &opts)
-                .unwrap_or_else(|e| eprintln!("{}", e))
+    let mut sources = vec![];
+    for source in status.sources.iter() {
+        let mut files: BTreeMap<&Path, u64> = BTreeMap::new();
+        for region in source.regions.iter() {
+            *files.entry(&region.path).or_insert(0) += region.length;
         }
 
-        Command::Enable { ref filter, .. } => {
-            do_set_state(filter.as_ref(), snapshot_dir, true, &opts)
-                .unwrap_or_else(|e| eprintln!("{}", e))
+        sources.push((source, files));
+    }
+
+    record.running = true;
+    record.locked_size = status.get_locked_size();
+    record.vmlck = get_locked_memory(status.pid);
+    record.sources = sources
+        .iter()
+        .map(|(source, files)| SourceRecord {
+            name: source.name.clone(),
+            path: source.path.clone(),
+            files: files.len(),
+            size: source.get_locked_size(),
+        })
+        .collect();
+
+    let vmlck = record.vmlck;
+
+    output.emit(Record::Status(record), || {
+        println!("prefault mlock (PID {}):", status.pid);
+
+        for (source, files) in sources.iter() {
+            println!(
+                "{} {} ({} files, {})",
+                source.path.display(),
+                source.name,
+                files.len(),
+                format_file_size(source.get_locked_size())
+            );
+
+            if opts.verbosity > 0 {
+                for (path, size) in files.iter() {
+                    println!("\t{} ({})", path.display(), format_file_size(*size));
+                }
+            }
         }
 
-        Command::Disable { ref filter, .. } => {
-            do_set_state(filter.as_ref(), snapshot_dir, false, &opts)
-                .unwrap_or_else(|e| eprintln!("{}", e))
+        println!(
+            "Total: {} locked",
+            format_file_size(status.get_locked_size())
+        );
+
+        if let Some(locked) = vmlck {
+            println!("VmLck: {}", format_file_size(locked));
         }
+    });
+
+    Ok(())
+}
+
+/// No filter matches everything
+fn match_filter<S: Subject>(filter: Option<&Filter>, subject: &S) -> bool {
+    filter.map(|f| f.matches(subject)).unwrap_or(true)
+}
+
+/// Parse the number of captures after which unseen files expire. 0 would
+/// drop every file of the existing snapshot, together with its hit count
+fn parse_expire_after(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_filter(filter: Option<&String>) -> Result<Option<Filter>, CommandError> {
+    match filter {
+        Some(filter) => filter
+            .parse()
+            .map(Some)
+            .map_err(CommandError::InvalidFilter),
+
+        None => Ok(None),
+    }
+}
+
+/// Run the command, returns the exit status
+fn run(opts: &Options, output: &mut Output) -> Result<i32, Error> {
+    // json is written once the command has finished, so the records of a
+    // command that keeps running would never show up
+    if opts.format == Format::Json && opts.cmd.is_long_running() {
+        return Err(CommandError::InvalidParamaters(
+            "--format json needs a command that finishes, use --format jsonl instead".to_string(),
+        )
+        .into());
+    }
+
+    let mut settings =
+        Settings::load(opts.config_file.as_ref()).map_err(CommandError::InvalidConfiguration)?;
+
+    let store = settings.get_snapshot_store();
+
+    if opts.cmd.uses_snapshot_names() {
+        do_migrate(&store, output)?;
+    }
 
-        Command::Show { ref filter, .. } => {
-            do_show(filter.as_ref(), snapshot_dir, &opts).unwrap_or_else(|e| eprintln!("{}", e))
+    let static_filelist_dir = settings.static_filelist_dir.clone();
+
+    let filter = parse_filter(opts.cmd.get_filter())?;
+
+    match &opts.cmd {
+        Command::List { .. } => do_list(filter.as_ref(), &static_filelist_dir, &store, output)?,
+
+        Command::Enable { .. } => do_set_state(filter.as_ref(), &store, true, output)?,
+
+        Command::Disable { .. } => do_set_state(filter.as_ref(), &store, false, output)?,
+
+        Command::Priority { priority, .. } => {
+            do_set_priority(filter.as_ref(), &store, *priority, output)?
         }
 
+        Command::Show { .. } => do_show(filter.as_ref(), &store, output)?,
+
         Command::Snapshot {
-            ref filter, pid, ..
+            pid,
+            elf,
+            unit,
+            cgroup,
+            residency,
+            tree,
+            merge,
+            expire_after,
+            ..
         } => {
-            do_snapshot(filter.as_ref(), pid, snapshot_dir, &opts)
-                .unwrap_or_else(|e| eprintln!("{}", e));
+            let options = SnapshotOptions {
+                residency: *residency,
+                tree: *tree,
+                merge: *merge,
+                expire_after: *expire_after,
+            };
+
+            let target = SnapshotTarget::new(
+                filter.as_ref(),
+                *pid,
+                elf.as_ref(),
+                unit.as_ref(),
+                cgroup.as_ref(),
+            )?;
+
+            do_snapshot(target, &options, &store, &settings.identity, output)?;
         }
 
-        Command::Incore {
-            ref filter, pid, ..
-        } => do_incore(filter.as_ref(), pid, snapshot_dir, &opts)
-            .unwrap_or_else(|e| eprintln!("{}", e)),
+        Command::Incore { pid, .. } => {
+            do_incore(filter.as_ref(), *pid, &store, &settings.identity, output)?
+        }
+
+        Command::Trace { time, command, .. } => {
+            do_trace(command, *time, &store, &settings.identity, opts, output)?
+        }
 
-        //Command::Trace { command: _, .. } => {
-        //println!("Trace subcommand is currently not implemented");
-        //}
+        Command::Daemon { delay, .. } => {
+            do_daemon(filter.as_ref(), *delay, &store, &settings, opts)?
+        }
 
-        Command::Remove { ref filter, .. } => {
-            do_remove(filter.as_ref(), snapshot_dir, &opts).unwrap_or_else(|e| eprintln!("{}", e))
+        Command::Verify { .. } => {
+            if do_verify(filter.as_ref(), &static_filelist_dir, &store, output)? > 0 {
+                return Ok(EXIT_STALE);
+            }
         }
 
-        Command::Cache { ref filter, .. } => {
-            do_cache(filter.as_ref(), &static_filelist_dir, &snapshot_dir, &opts)
-                .unwrap_or_else(|e| eprintln!("{}", e))
+        Command::Repair { .. } => {
+            let failed = do_repair(
+                filter.as_ref(),
+                &static_filelist_dir,
+                &store,
+                &settings.identity,
+                output,
+            )?;
+
+            if failed > 0 {
+                return Ok(EXIT_STALE);
+            }
         }
 
-        Command::Mlock { ref filter, .. } => {
-            do_mlock(filter.as_ref(), &static_filelist_dir, &snapshot_dir, &opts)
-                .unwrap_or_else(|e| eprintln!("{}", e));
-            println!("Going to sleep now");
+        Command::Remove { .. } => do_remove(filter.as_ref(), &store, output)?,
 
-            if unsafe { libc::isatty(0) == 1 } {
-                loop {
+        Command::Cache {
+            align,
+            unit,
+            adaptive,
+            max_pause,
+            watch,
+            ..
+        } => {
+            let options = CacheOptions {
+                align: *align,
+                adaptive: Some(Duration::from_secs(max_pause.unwrap_or(60))).filter(|_| *adaptive),
+                watch: *watch,
+            };
+
+            let workers =
+                Workers::new(&settings.io_limits).map_err(CommandError::ExecutionError)?;
+
+            do_cache(
+                filter.as_ref(),
+                unit.as_ref(),
+                &settings,
+                &options,
+                &workers,
+                opts,
+                output,
+            )?;
+
+            if options.watch {
+                let interval = settings.watch_limits.interval;
+
+                if output.is_table() {
+                    println!(
+                        "Checking every {}s for files that have been evicted",
+                        interval.as_secs()
+                    );
+                }
+
+                let mut next_check = Instant::now() + interval;
+
+                while RUNNING.load(Ordering::SeqCst) {
                     thread::sleep(Duration::from_millis(1000));
 
-                    if !RUNNING.load(Ordering::SeqCst) {
-                        break;
+                    if Instant::now() < next_check {
+                        continue;
+                    }
+
+                    if let Err(e) = do_rewarm(
+                        filter.as_ref(),
+                        unit.as_ref(),
+                        &settings,
+                        options.align,
+                        &workers,
+                        opts,
+                        output,
+                    ) {
+                        output.error("cache", e);
+                    }
+
+                    next_check = Instant::now() + interval;
+                }
+
+                if output.is_table() {
+                    println!("Exiting");
+                }
+            }
+        }
+
+        Command::Mlock { align, .. } => {
+            let align = *align;
+            let mut registry = LockRegistry::new(align);
+
+            // locking may take a while, a reload requested meanwhile is
+            // handled once it is done
+            let action = SigAction::new(
+                SigHandler::Handler(handle_sighup),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            unsafe { signal::sigaction(Signal::SIGHUP, &action) }
+                .expect("Error setting SIGHUP handler");
+
+            if let Err(e) = do_mlock(
+                filter.as_ref(),
+                &settings,
+                &mut registry,
+                align,
+                opts,
+                output,
+            ) {
+                output.error("mlock", e);
+            }
+
+            if output.is_table() {
+                println!("Going to sleep now");
+            }
+
+            while RUNNING.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1000));
+
+                if RELOAD.swap(false, Ordering::SeqCst) {
+                    if output.is_table() {
+                        println!("Reloading configuration");
+                    }
+
+                    match Settings::load(opts.config_file.as_ref()) {
+                        Ok(s) => settings = s,
+
+                        Err(e) => output.error("Could not load configuration", e),
+                    }
+
+                    if let Err(e) = do_mlock(
+                        filter.as_ref(),
+                        &settings,
+                        &mut registry,
+                        align,
+                        opts,
+                        output,
+                    ) {
+                        output.error("mlock", e);
                     }
                 }
-            } else {
-                thread::sleep(Duration::from_millis(std::u64::MAX));
             }
 
-            println!("Exiting");
+            for (path, e) in registry.unlock_all() {
+                output.error(path.display(), e);
+            }
+
+            let _ = fs::remove_file(&settings.status_file);
+
+            if output.is_table() {
+                println!("Exiting");
+            }
+        }
+
+        Command::Benchmark { runs, align, .. } => {
+            do_benchmark(filter.as_ref(), &settings, *align, *runs, output)?
         }
+
+        Command::Status => do_status(&settings.status_file, opts, output)?,
+
+        Command::Config {
+            cmd: ConfigCommand::Dump,
+        } => do_config_dump(&settings, output),
     }
+
+    Ok(0)
+}
+
+fn main() {
+    let r = RUNNING.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let mut opts = match Options::from_args_safe() {
+        Ok(opts) => opts,
+
+        // --help and --version
+        Err(e) if !e.use_stderr() => e.exit(),
+
+        Err(e) => {
+            eprintln!("{}", e.message);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    // the log output of -v would be mixed into the records
+    if opts.format != Format::Table {
+        opts.verbosity = 0;
+    }
+
+    let mut output = Output::new(opts.format);
+
+    let status = match run(&opts, &mut output) {
+        Ok(status) => status,
+
+        Err(e) => {
+            output.fatal(&e);
+            get_exit_status(&e)
+        }
+    };
+
+    output.finish();
+
+    std::process::exit(status);
 }
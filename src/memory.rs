@@ -18,251 +18,596 @@
     along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use libc;
+#![allow(non_local_definitions)]
+
+use failure::Fail;
+use lazy_static::lazy_static;
 use rayon::prelude::*;
-use std::ffi;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
-use std::os::unix::io::IntoRawFd;
-use std::path::PathBuf;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::str::FromStr;
 
+use crate::layout::{self, IoOrder};
+use crate::snapshot::*;
 use crate::util;
-use crate::Options;
+use crate::workers::Workers;
 
 const MAX_READAHEAD: usize = 10 * 1024 * 1024;
 
+/// Used if the page size can not be queried
+const DEFAULT_PAGE_SIZE: u64 = 4096;
+
+lazy_static! {
+    static ref PAGE_SIZE: u64 = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as u64,
+        _ => DEFAULT_PAGE_SIZE,
+    };
+}
+
+/// Default size of the kernel's readahead window (`read_ahead_kb`)
+const READAHEAD_WINDOW: u64 = 128 * 1024;
+
+const DEFAULT_HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+const HPAGE_PMD_SIZE: &str = "/sys/kernel/mm/transparent_hugepage/hpage_pmd_size";
+
+/// From linux/capability.h
+const CAP_IPC_LOCK: u32 = 14;
+
+#[derive(Fail, Debug)]
+pub enum MemoryError {
+    #[fail(display = "{}", _0)]
+    Open(#[fail(cause)] io::Error),
+
+    #[fail(display = "{} failed: {}", _0, _1)]
+    SystemCall(&'static str, #[fail(cause)] io::Error),
+}
+
+/// The error of the system call that just failed
+fn last_error(call: &'static str) -> MemoryError {
+    MemoryError::SystemCall(call, io::Error::last_os_error())
+}
+
+/// Look up the files, so that their dentries and inodes are cached. Files
+/// that can not be opened are reported by `prefault_file_mappings`.
 pub fn prime_dentry_cache(m: &[PathBuf]) {
     m.par_iter().for_each(|mapping| {
-        println!("{}", mapping.display());
-        match File::open(&mapping) {
-            Ok(f) => {
-                let fd = f.into_raw_fd();
+        let _ = fs::metadata(mapping);
+    })
+}
 
-                let mut stat: libc::stat = unsafe { std::mem::zeroed() };
-                let result = unsafe { libc::fstat(fd, &mut stat) };
-                if result != 0 {
-                    unsafe {
-                        let f = ffi::CString::new("fstat").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                }
+/// The size of the pages of the system, which mincore(2) reports on
+pub fn get_page_size() -> u64 {
+    *PAGE_SIZE
+}
 
-                unsafe {
-                    libc::close(fd);
-                }
-            }
+/// Granularity to which mapped ranges are rounded out before prefaulting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alignment {
+    Page,
+    HugePage,
+    Readahead,
+}
 
-            Err(e) => println!("{}: {}", mapping.display(), e),
+impl FromStr for Alignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "page" => Ok(Alignment::Page),
+            "hugepage" => Ok(Alignment::HugePage),
+            "readahead" => Ok(Alignment::Readahead),
+            _ => Err(format!(
+                "Invalid alignment '{}', expected one of: page, hugepage, readahead",
+                s
+            )),
         }
-    })
+    }
 }
 
-pub fn prefault_file_mappings(m: &[PathBuf]) -> io::Result<()> {
-    m.par_iter().for_each(|mapping| {
-        println!("{}", mapping.display());
+impl Alignment {
+    pub fn get_size(self) -> u64 {
+        match self {
+            Alignment::Page => get_page_size(),
 
-        match File::open(&mapping) {
-            Ok(f) => {
-                let fd = f.into_raw_fd();
+            Alignment::HugePage => fs::read_to_string(HPAGE_PMD_SIZE)
+                .ok()
+                .and_then(|s| s.trim().parse().ok())
+                .unwrap_or(DEFAULT_HUGE_PAGE_SIZE),
 
-                let result = unsafe { libc::readahead(fd, 0, MAX_READAHEAD) };
-                if result != 0 {
-                    unsafe {
-                        let f = ffi::CString::new("readahead").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                }
+            Alignment::Readahead => READAHEAD_WINDOW,
+        }
+    }
+}
 
-                let mut stat: libc::stat = unsafe { std::mem::zeroed() };
-                let result = unsafe { libc::fstat(fd, &mut stat) };
-                if result != 0 {
-                    unsafe {
-                        let f = ffi::CString::new("fstat").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                }
+/// An upper bound for the amount of memory to use, either in bytes or in
+/// percent of the total memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryLimit {
+    Bytes(u64),
+    Percent(u64),
+}
 
-                let addr: *mut core::ffi::c_void = unsafe {
-                    libc::mmap(
-                        ptr::null_mut(),
-                        stat.st_size as usize,
-                        libc::PROT_READ,
-                        libc::MAP_PRIVATE,
-                        fd,
-                        0,
-                    )
-                };
-                if addr.is_null() {
-                    unsafe {
-                        let f = ffi::CString::new("mmap").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
+impl FromStr for MemoryLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || {
+            format!(
+                "Invalid memory limit '{}', expected a size like 512M or 2G, or a percentage like 25%",
+                s
+            )
+        };
+
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.trim().parse::<u64>() {
+                Ok(percent) if percent <= 100 => Ok(MemoryLimit::Percent(percent)),
+
+                _ => Err(invalid()),
+            };
+        }
+
+        util::parse_size(s)
+            .map(MemoryLimit::Bytes)
+            .ok_or_else(invalid)
+    }
+}
+
+impl MemoryLimit {
+    pub fn get_bytes(self) -> u64 {
+        match self {
+            MemoryLimit::Bytes(bytes) => bytes,
+
+            MemoryLimit::Percent(percent) => get_total_memory() / 100 * percent,
+        }
+    }
+}
+
+/// Read MemTotal from /proc/meminfo
+pub fn get_total_memory() -> u64 {
+    fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| {
+            meminfo
+                .lines()
+                .find(|l| l.starts_with("MemTotal:"))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|kb| kb.parse::<u64>().ok())
+        })
+        .map(|kb| kb * 1024)
+        .unwrap_or(0)
+}
+
+/// Get the soft RLIMIT_MEMLOCK of the current process, if it is limited and
+/// can be read. Processes with CAP_IPC_LOCK are not limited by it.
+pub fn get_mlock_limit() -> Option<u64> {
+    if has_capability(CAP_IPC_LOCK) {
+        return None;
+    }
+
+    let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) };
+    if result != 0 {
+        return None;
+    }
+
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        None
+    } else {
+        Some(limit.rlim_cur as u64)
+    }
+}
+
+/// Whether the effective capability set of the current process contains `cap`
+fn has_capability(cap: u32) -> bool {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|l| l.starts_with("CapEff:"))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|caps| u64::from_str_radix(caps, 16).ok())
+        })
+        .map(|caps| caps & (1 << cap) != 0)
+        .unwrap_or(false)
+}
+
+/// Compute the byte ranges of `file` that should be faulted in, rounded out
+/// to `alignment` and clamped to the current size of the file. Recorded
+/// page residency takes precedence over the mapped ranges.
+pub fn get_aligned_ranges(file: &MappedFile, size: u64, alignment: Alignment) -> Vec<MappedRange> {
+    let align = alignment.get_size();
+
+    let base_ranges = file.get_wanted_ranges(get_page_size());
+
+    let mut ranges: Vec<MappedRange> = if base_ranges.is_empty() && file.resident.is_none() {
+        vec![MappedRange {
+            offset: 0,
+            length: size,
+        }]
+    } else {
+        base_ranges
+            .iter()
+            .filter(|r| r.offset < size)
+            .map(|r| {
+                let offset = r.offset - (r.offset % align);
+                let end = (r.end().div_ceil(align) * align).min(size);
+
+                MappedRange {
+                    offset,
+                    length: end - offset,
                 }
+            })
+            .collect()
+    };
+
+    ranges.retain(|r| r.length > 0);
+    coalesce_ranges(&mut ranges);
+
+    ranges
+}
+
+fn get_file_size(fd: RawFd) -> Result<u64, MemoryError> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::fstat(fd, &mut stat) };
+    if result != 0 {
+        return Err(last_error("fstat"));
+    }
+
+    Ok(stat.st_size as u64)
+}
+
+fn map_range(fd: RawFd, range: &MappedRange) -> Result<*mut core::ffi::c_void, MemoryError> {
+    let addr: *mut core::ffi::c_void = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            range.length as usize,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            fd,
+            range.offset as libc::off_t,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(last_error("mmap"));
+    }
+
+    Ok(addr)
+}
+
+fn readahead(fd: RawFd, range: &MappedRange) -> Result<(), MemoryError> {
+    let result = unsafe {
+        libc::readahead(
+            fd,
+            range.offset as libc::off64_t,
+            (range.length as usize).min(MAX_READAHEAD),
+        )
+    };
+    if result != 0 {
+        return Err(last_error("readahead"));
+    }
+
+    Ok(())
+}
+
+/// Fault the files into the page cache, in the threads of `workers`, and in
+/// their order. Returns the errors, by file.
+pub fn prefault_file_mappings(
+    m: &[MappedFile],
+    alignment: Alignment,
+    workers: &Workers,
+) -> Vec<(PathBuf, MemoryError)> {
+    let prefault = |mapping: &MappedFile| -> Vec<(PathBuf, MemoryError)> {
+        prefault_file(mapping, alignment, workers)
+            .into_iter()
+            .map(|e| (mapping.path.clone(), e))
+            .collect()
+    };
+
+    workers.install(|| match workers.get_order() {
+        IoOrder::None => m.par_iter().flat_map(prefault).collect(),
+
+        // one file after the other per device, the devices in parallel
+        order => layout::group_by_device(m, order)
+            .par_iter()
+            .flat_map(|files| files.iter().flat_map(prefault).collect::<Vec<_>>())
+            .collect(),
+    })
+}
+
+fn prefault_file(
+    mapping: &MappedFile,
+    alignment: Alignment,
+    workers: &Workers,
+) -> Vec<MemoryError> {
+    let f = match File::open(&mapping.path) {
+        Ok(f) => f,
+        Err(e) => return vec![MemoryError::Open(e)],
+    };
+
+    let size = match get_file_size(f.as_raw_fd()) {
+        Ok(size) => size,
+        Err(e) => return vec![e],
+    };
+
+    let mut errors = vec![];
+
+    for range in get_aligned_ranges(mapping, size, alignment) {
+        workers.acquire(range.length);
+
+        if let Err(e) = readahead(f.as_raw_fd(), &range) {
+            errors.push(e);
+        }
 
+        match map_range(f.as_raw_fd(), &range) {
+            Ok(addr) => {
                 let result =
-                    unsafe { libc::madvise(addr, stat.st_size as usize, libc::MADV_WILLNEED) };
+                    unsafe { libc::madvise(addr, range.length as usize, libc::MADV_WILLNEED) };
                 if result != 0 {
-                    unsafe {
-                        let f = ffi::CString::new("madvise").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
+                    errors.push(last_error("madvise"));
                 }
 
                 unsafe {
-                    libc::close(fd);
+                    libc::munmap(addr, range.length as usize);
                 }
             }
 
-            Err(e) => println!("{}: {}", mapping.display(), e),
+            Err(e) => errors.push(e),
         }
-    });
+    }
+
+    errors
+}
+
+/// Drop the pages of a file from the page cache, pages that are mapped by
+/// other processes or dirty stay resident
+pub fn evict_file<P: AsRef<Path>>(path: P) -> Result<(), MemoryError> {
+    let f = File::open(path.as_ref()).map_err(MemoryError::Open)?;
+
+    let result = unsafe { libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if result != 0 {
+        return Err(MemoryError::SystemCall(
+            "posix_fadvise",
+            io::Error::from_raw_os_error(result),
+        ));
+    }
 
     Ok(())
 }
 
-pub fn mlock_file_mappings(m: &[PathBuf], opts: &Options) -> io::Result<()> {
-    m.par_iter().for_each(|mapping| {
-        if opts.verbosity > 1 {
-            println!("{}", mapping.display());
-        }
+/// A byte range of a file that is mapped and locked into our address space
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockedRegion {
+    pub path: PathBuf,
+    pub addr: usize,
+    pub length: usize,
 
-        match File::open(&mapping) {
-            Ok(f) => {
-                let fd = f.into_raw_fd();
+    /// The snapshot or static file list the region has been locked for
+    pub source: PathBuf,
+}
 
-                let result = unsafe { libc::readahead(fd, 0, MAX_READAHEAD) };
-                if result != 0 {
-                    unsafe {
-                        let f = ffi::CString::new("readahead").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                }
+/// Lock the files into memory. The mappings are kept, and returned so that
+/// they may be released again with `munlock_regions`, along with the errors
+/// by file.
+pub fn mlock_file_mappings<P: AsRef<Path>>(
+    m: &[MappedFile],
+    alignment: Alignment,
+    source: P,
+) -> (Vec<LockedRegion>, Vec<(PathBuf, MemoryError)>) {
+    let source = source.as_ref();
+
+    let results: Vec<(Vec<LockedRegion>, Vec<MemoryError>)> = m
+        .par_iter()
+        .map(|mapping| mlock_file(mapping, alignment, source))
+        .collect();
+
+    let mut regions = vec![];
+    let mut errors = vec![];
+
+    for (mapping, (locked, failed)) in m.iter().zip(results) {
+        regions.extend(locked);
+        errors.extend(failed.into_iter().map(|e| (mapping.path.clone(), e)));
+    }
 
-                let mut stat: libc::stat = unsafe { std::mem::zeroed() };
-                let result = unsafe { libc::fstat(fd, &mut stat) };
-                if result != 0 {
-                    unsafe {
-                        let f = ffi::CString::new("fstat").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                }
+    (regions, errors)
+}
 
-                let addr: *mut core::ffi::c_void = unsafe {
-                    libc::mmap(
-                        ptr::null_mut(),
-                        stat.st_size as usize,
-                        libc::PROT_READ,
-                        libc::MAP_PRIVATE,
-                        fd,
-                        0,
-                    )
-                };
-                if addr.is_null() {
-                    unsafe {
-                        let f = ffi::CString::new("mmap").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                }
+fn mlock_file(
+    mapping: &MappedFile,
+    alignment: Alignment,
+    source: &Path,
+) -> (Vec<LockedRegion>, Vec<MemoryError>) {
+    let mut regions = vec![];
+
+    let f = match File::open(&mapping.path) {
+        Ok(f) => f,
+        Err(e) => return (regions, vec![MemoryError::Open(e)]),
+    };
+
+    let size = match get_file_size(f.as_raw_fd()) {
+        Ok(size) => size,
+        Err(e) => return (regions, vec![e]),
+    };
+
+    let mut errors = vec![];
+
+    for range in get_aligned_ranges(mapping, size, alignment) {
+        if let Err(e) = readahead(f.as_raw_fd(), &range) {
+            errors.push(e);
+        }
 
-                let result = unsafe { libc::mlock(addr, stat.st_size as usize) };
+        match map_range(f.as_raw_fd(), &range) {
+            Ok(addr) => {
+                let result = unsafe { libc::mlock(addr, range.length as usize) };
                 if result != 0 {
+                    errors.push(last_error("mlock"));
+
                     unsafe {
-                        let f = ffi::CString::new("mlock").unwrap();
-                        libc::perror(f.as_ptr());
+                        libc::munmap(addr, range.length as usize);
                     }
-                }
 
-                unsafe {
-                    libc::close(fd);
+                    continue;
                 }
+
+                // the mapping outlives the file descriptor
+                regions.push(LockedRegion {
+                    path: mapping.path.clone(),
+                    addr: addr as usize,
+                    length: range.length as usize,
+                    source: source.to_path_buf(),
+                });
             }
 
-            Err(e) => println!("{}: {}", mapping.display(), e),
+            Err(e) => errors.push(e),
         }
-    });
+    }
 
-    Ok(())
+    (regions, errors)
 }
 
-pub fn print_fincore(m: &[PathBuf]) -> io::Result<()> {
-    for mapping in m.iter() {
-        match File::open(&mapping) {
-            Ok(f) => {
-                let fd = f.into_raw_fd();
+/// Unlock and unmap regions previously locked by `mlock_file_mappings`.
+/// Returns the errors, by file.
+pub fn munlock_regions(regions: &[LockedRegion]) -> Vec<(PathBuf, MemoryError)> {
+    let mut result = vec![];
 
-                let mut stat: libc::stat = unsafe { std::mem::zeroed() };
-                let result = unsafe { libc::fstat(fd, &mut stat) };
-                if result != 0 {
-                    unsafe {
-                        let f = ffi::CString::new("fstat").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                }
+    for region in regions.iter() {
+        let addr = region.addr as *mut core::ffi::c_void;
 
-                let addr: *mut core::ffi::c_void = unsafe {
-                    libc::mmap(
-                        ptr::null_mut(),
-                        stat.st_size as usize,
-                        libc::PROT_NONE,
-                        libc::MAP_PRIVATE,
-                        fd,
-                        0,
-                    )
-                };
-                if addr.is_null() {
-                    unsafe {
-                        let f = ffi::CString::new("mmap").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                }
+        if unsafe { libc::munlock(addr, region.length) } != 0 {
+            result.push((region.path.clone(), last_error("munlock")));
+        }
 
-                let mut pages = Vec::with_capacity(((stat.st_size + 4096) / 4096) as usize);
-                let result =
-                    unsafe { libc::mincore(addr, stat.st_size as usize, pages.as_mut_ptr()) };
-                if result != 0 {
-                    unsafe {
-                        let f = ffi::CString::new("mincore").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                } else {
-                    unsafe {
-                        pages.set_len(((stat.st_size + 4096) / 4096) as usize);
-                    }
-                }
+        if unsafe { libc::munmap(addr, region.length) } != 0 {
+            result.push((region.path.clone(), last_error("munmap")));
+        }
+    }
 
-                let result = unsafe { libc::munmap(addr, stat.st_size as usize) };
-                if result != 0 {
-                    unsafe {
-                        let f = ffi::CString::new("munmap").unwrap();
-                        libc::perror(f.as_ptr());
-                    }
-                }
+    result
+}
 
-                let mut page_cnt = 0;
-                for page in pages.iter() {
-                    if page & 0x1 != 0 {
-                        page_cnt += 1;
-                    }
-                }
+/// Query the page cache residency of the first `size` bytes of `fd`, one
+/// byte per page as returned by mincore(2)
+fn get_page_residency(fd: RawFd, size: u64) -> Result<Vec<u8>, MemoryError> {
+    if size == 0 {
+        return Ok(vec![]);
+    }
 
-                let fincore_percentage = (page_cnt * 100) / ((stat.st_size + 4096) / 4096);
-                println!(
-                    "{:3}% {:5} {} ({})",
-                    fincore_percentage,
-                    page_cnt,
-                    mapping.display(),
-                    util::format_file_size(stat.st_size as u64),
-                );
+    let addr: *mut core::ffi::c_void = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size as usize,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE,
+            fd,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(last_error("mmap"));
+    }
 
-                unsafe {
-                    libc::close(fd);
-                }
-            }
+    let mut pages = vec![0u8; size.div_ceil(get_page_size()) as usize];
+    let result = unsafe { libc::mincore(addr, size as usize, pages.as_mut_ptr()) };
+    // save errno of mincore before munmap may overwrite it
+    let result = if result != 0 {
+        Err(last_error("mincore"))
+    } else {
+        Ok(pages)
+    };
+
+    if unsafe { libc::munmap(addr, size as usize) } != 0 && result.is_ok() {
+        return Err(last_error("munmap"));
+    }
+
+    result
+}
 
-            Err(e) => println!("{}: {}", mapping.display(), e),
+/// Run-length encode a mincore(2) vector into runs of resident pages
+fn encode_page_runs(pages: &[u8]) -> Vec<PageRun> {
+    let mut result: Vec<PageRun> = vec![];
+
+    for (index, page) in pages.iter().enumerate() {
+        if page & 0x1 == 0 {
+            continue;
+        }
+
+        let index = index as u64;
+        match result.last_mut() {
+            Some(run) if run.first + run.count == index => run.count += 1,
+
+            _ => result.push(PageRun {
+                first: index,
+                count: 1,
+            }),
         }
     }
 
-    Ok(())
+    result
+}
+
+/// Get the pages of a file that are currently resident in the page cache
+pub fn get_resident_pages<T: AsRef<Path>>(path: T) -> Result<Vec<PageRun>, MemoryError> {
+    let f = File::open(path.as_ref()).map_err(MemoryError::Open)?;
+    let size = get_file_size(f.as_raw_fd())?;
+
+    Ok(encode_page_runs(&get_page_residency(f.as_raw_fd(), size)?))
+}
+
+/// How much of a file is resident in the page cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct Residency {
+    pub size: u64,
+    pub resident_pages: u64,
+}
+
+impl Residency {
+    pub fn get_percentage(&self) -> u64 {
+        (self.resident_pages * 100) / self.size.div_ceil(get_page_size()).max(1)
+    }
+}
+
+pub fn get_file_residency<T: AsRef<Path>>(path: T) -> Result<Residency, MemoryError> {
+    let f = File::open(path.as_ref()).map_err(MemoryError::Open)?;
+    let size = get_file_size(f.as_raw_fd())?;
+
+    let pages = get_page_residency(f.as_raw_fd(), size)?;
+
+    Ok(Residency {
+        size,
+        resident_pages: pages.iter().filter(|page| *page & 0x1 != 0).count() as u64,
+    })
+}
+
+/// How much of the byte ranges of `mapping` that `prefault_file_mappings`
+/// faults in is resident in the page cache
+pub fn get_mapping_residency(
+    mapping: &MappedFile,
+    alignment: Alignment,
+) -> Result<Residency, MemoryError> {
+    let f = File::open(&mapping.path).map_err(MemoryError::Open)?;
+    let size = get_file_size(f.as_raw_fd())?;
+
+    let pages = get_page_residency(f.as_raw_fd(), size)?;
+
+    let mut result = Residency {
+        size: 0,
+        resident_pages: 0,
+    };
+
+    for range in get_aligned_ranges(mapping, size, alignment) {
+        let first = (range.offset / get_page_size()) as usize;
+        let last = (range.end().div_ceil(get_page_size()) as usize).min(pages.len());
+
+        result.size += range.length;
+        result.resident_pages += pages[first.min(last)..last]
+            .iter()
+            .filter(|page| *page & 0x1 != 0)
+            .count() as u64;
+    }
+
+    Ok(result)
 }
@@ -0,0 +1,302 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// How the results of a command are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Human readable text
+    Table,
+
+    /// A single JSON array of all records, written when the command finishes
+    Json,
+
+    /// One JSON object per line, written as soon as it is available
+    Jsonl,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(format!(
+                "Invalid format '{}', expected one of: table, json, jsonl",
+                s
+            )),
+        }
+    }
+}
+
+/// A file of a snapshot, as listed by `prefault show`
+#[derive(Debug, Serialize)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hits: Option<u64>,
+    pub missed: u32,
+    pub processes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotRecord {
+    pub hash: String,
+    pub key: String,
+    pub command: String,
+    pub path: PathBuf,
+    pub enabled: bool,
+    pub priority: i32,
+    pub files: usize,
+
+    /// The total size of the files that currently exist
+    pub size: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exe: Option<PathBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+
+    /// Only filled in by `prefault show`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mappings: Vec<FileRecord>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SourceRecord {
+    pub name: String,
+    pub path: PathBuf,
+    pub files: usize,
+    pub size: u64,
+}
+
+/// The outcome of `prefault cache` and of every (re)load of `prefault mlock`
+#[derive(Debug, Serialize)]
+pub struct WorkSetRecord {
+    pub command: String,
+
+    /// Whether the files have been locked, or only faulted into the page cache
+    pub locked: bool,
+
+    pub sources: Vec<SourceRecord>,
+    pub size: u64,
+    pub budget: Option<u64>,
+    pub duplicates: usize,
+    pub duplicate_size: u64,
+
+    /// Sources that did not fit into the budget, with the files and bytes
+    /// that were left out
+    pub skipped: Vec<SourceRecord>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusRecord {
+    pub pid: Option<libc::pid_t>,
+
+    /// False if there is no status file, or its process is not running
+    pub running: bool,
+
+    pub sources: Vec<SourceRecord>,
+    pub locked_size: u64,
+    pub vmlck: Option<u64>,
+}
+
+/// A unit of the structured output, written as a JSON object with a `type`
+/// field that names the variant
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    FileList {
+        path: PathBuf,
+        files: usize,
+        size: u64,
+    },
+
+    Snapshot(SnapshotRecord),
+
+    /// A snapshot that has been written by `snapshot`, `trace` or `repair`
+    Saved {
+        path: PathBuf,
+        key: String,
+        files: usize,
+        merged: bool,
+    },
+
+    /// How much of a file is resident in the page cache
+    Residency {
+        source: String,
+        path: PathBuf,
+        size: u64,
+        resident_pages: u64,
+        percent: u64,
+    },
+
+    Stale {
+        source: String,
+        path: PathBuf,
+        state: String,
+    },
+
+    Removed {
+        path: PathBuf,
+    },
+
+    /// A snapshot that was named after the hash of an earlier version
+    Renamed {
+        path: PathBuf,
+        new_path: PathBuf,
+    },
+
+    WorkSet(WorkSetRecord),
+
+    /// How `cache --adaptive` backed off, in seconds, and the files it
+    /// skipped
+    Pressure {
+        throttled: f64,
+        paused: f64,
+        skipped_files: usize,
+        skipped_size: u64,
+    },
+
+    /// A check of `cache --watch`, with the files that have been faulted in
+    /// again and the bytes that have been read for them, and the files that
+    /// did not fit into the I/O budget
+    Rewarm {
+        checked: usize,
+        rewarmed: Vec<PathBuf>,
+        rewarmed_size: u64,
+        deferred: usize,
+        deferred_size: u64,
+    },
+
+    /// An order of `benchmark`, with the mean and the best time of its runs
+    /// in seconds, and the mean read rate in bytes per second
+    Benchmark {
+        order: String,
+        runs: u32,
+        files: usize,
+        size: u64,
+        mean: f64,
+        best: f64,
+        rate: u64,
+    },
+
+    Status(StatusRecord),
+
+    Setting {
+        key: String,
+        value: String,
+        source: String,
+        known: bool,
+    },
+
+    /// A fatal error ends the command, other errors only concern the
+    /// subject, like a single file
+    Error {
+        subject: Option<String>,
+        message: String,
+        fatal: bool,
+    },
+}
+
+/// Writes records in the requested format, and falls back to the given
+/// human readable text in table format
+#[derive(Debug)]
+pub struct Output {
+    format: Format,
+    records: Vec<serde_json::Value>,
+}
+
+impl Output {
+    pub fn new(format: Format) -> Self {
+        Output {
+            format,
+            records: vec![],
+        }
+    }
+
+    pub fn is_table(&self) -> bool {
+        self.format == Format::Table
+    }
+
+    /// Emit a record, `table` prints it in table format
+    pub fn emit<F: FnOnce()>(&mut self, record: Record, table: F) {
+        let value = match self.format {
+            Format::Table => return table(),
+
+            // only paths that are not valid UTF-8 fail to serialize
+            _ => serde_json::to_value(&record).unwrap_or_else(|e| {
+                serde_json::json!({
+                    "type": "error",
+                    "subject": null,
+                    "message": e.to_string(),
+                    "fatal": false,
+                })
+            }),
+        };
+
+        match self.format {
+            Format::Jsonl => println!("{}", value),
+            _ => self.records.push(value),
+        }
+    }
+
+    /// Report an error that concerns a single subject, like a file
+    pub fn error<S: fmt::Display, E: fmt::Display>(&mut self, subject: S, error: E) {
+        let record = Record::Error {
+            subject: Some(subject.to_string()),
+            message: error.to_string(),
+            fatal: false,
+        };
+
+        self.emit(record, || eprintln!("{}: {}", subject, error));
+    }
+
+    /// Report the error that ended the command
+    pub fn fatal<E: fmt::Display>(&mut self, error: E) {
+        let record = Record::Error {
+            subject: None,
+            message: error.to_string(),
+            fatal: true,
+        };
+
+        self.emit(record, || eprintln!("{}", error));
+    }
+
+    /// Write the collected records in json format
+    pub fn finish(self) {
+        if self.format == Format::Json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&self.records).unwrap_or_default()
+            );
+        }
+    }
+}
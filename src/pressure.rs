@@ -0,0 +1,321 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+#![allow(non_local_definitions)]
+
+use failure::{Error, Fail};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::memory::{self, Alignment, MemoryError};
+use crate::process::*;
+use crate::snapshot::MappedFile;
+use crate::workers::Workers;
+
+const PSI_MEMORY: &str = "/proc/pressure/memory";
+
+/// Readings taken closer together than this reuse the previous decision
+const MIN_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of files that are faulted in at once while there is no pressure
+const BATCH_SIZE: usize = 16;
+
+/// How long to wait after every file while the pressure is elevated
+const THROTTLE_DELAY: Duration = Duration::from_millis(100);
+
+/// How often the pressure is checked again while paused
+const PAUSE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Fail, Debug)]
+pub enum PressureError {
+    #[fail(display = "Could not read MemAvailable from /proc/meminfo")]
+    NoMemAvailable,
+}
+
+/// A reading of the memory pressure of the system, and of our own cgroup
+#[derive(Debug, Clone)]
+pub struct PressureSample {
+    /// The `some` line of /proc/pressure/memory: the total time in
+    /// microseconds in which at least one task stalled on memory, and the
+    /// share of the last 10 seconds in percent. `None` if the kernel does not
+    /// provide pressure stall information.
+    pub stall_total: Option<u64>,
+    pub stall_avg10: Option<f64>,
+
+    /// MemAvailable from /proc/meminfo, in bytes
+    pub available: u64,
+
+    /// The sum of the high, max and oom counters in `memory.events` of our
+    /// cgroup, which grows whenever the cgroup hits its memory limits
+    pub cgroup_events: u64,
+
+    pub taken_at: Instant,
+}
+
+impl PressureSample {
+    pub fn read() -> Result<Self, Error> {
+        let (stall_total, stall_avg10) = match read_psi_some() {
+            Some((total, avg10)) => (Some(total), Some(avg10)),
+            None => (None, None),
+        };
+
+        Ok(PressureSample {
+            stall_total,
+            stall_avg10,
+            available: get_available_memory().ok_or(PressureError::NoMemAvailable)?,
+            cgroup_events: get_cgroup_memory_events(),
+            taken_at: Instant::now(),
+        })
+    }
+}
+
+/// Parse `some avg10=0.00 avg60=0.00 avg300=0.00 total=0`
+fn read_psi_some() -> Option<(u64, f64)> {
+    let psi = fs::read_to_string(PSI_MEMORY).ok()?;
+    let line = psi.lines().find(|l| l.starts_with("some "))?;
+
+    let field = |name: &str| {
+        line.split_whitespace()
+            .find_map(|f| f.strip_prefix(name).and_then(|v| v.strip_prefix('=')))
+    };
+
+    Some((field("total")?.parse().ok()?, field("avg10")?.parse().ok()?))
+}
+
+/// Read MemAvailable from /proc/meminfo
+fn get_available_memory() -> Option<u64> {
+    fs::read_to_string("/proc/meminfo")
+        .ok()?
+        .lines()
+        .find(|l| l.starts_with("MemAvailable:"))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+/// The `memory.events` file of the cgroup of the current process, the root
+/// cgroup and cgroup v1 hierarchies do not have one
+fn get_cgroup_memory_events_path() -> Option<PathBuf> {
+    let cgroup = Process::new(unsafe { libc::getpid() })
+        .and_then(|p| p.get_cgroup())
+        .ok()?;
+
+    let path = get_cgroup_dir(&cgroup).ok()?.join("memory.events");
+
+    Some(path).filter(|p| p.exists())
+}
+
+fn get_cgroup_memory_events() -> u64 {
+    let events = match get_cgroup_memory_events_path().and_then(|p| fs::read_to_string(p).ok()) {
+        Some(events) => events,
+        None => return 0,
+    };
+
+    events
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+
+            match (fields.next(), fields.next()) {
+                (Some("high"), Some(count))
+                | (Some("max"), Some(count))
+                | (Some("oom"), Some(count)) => count.parse::<u64>().ok(),
+
+                _ => None,
+            }
+        })
+        .sum()
+}
+
+/// How prefaulting should continue
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Proceed,
+
+    /// Fault in one file at a time, and wait in between
+    Throttle,
+
+    /// Wait until the pressure falls
+    Pause,
+}
+
+/// When to slow down and when to pause prefaulting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PressureLimits {
+    /// The share of time in percent in which tasks stalled on memory, at
+    /// which prefaulting is slowed down and paused
+    pub throttle: f64,
+    pub pause: f64,
+
+    /// Prefaulting pauses while less memory is available, in bytes
+    pub min_available: u64,
+}
+
+/// Decides from the memory pressure how prefaulting should continue
+#[derive(Debug)]
+pub struct Governor {
+    limits: PressureLimits,
+    last: PressureSample,
+    action: Action,
+}
+
+impl Governor {
+    pub fn new(limits: PressureLimits) -> Result<Self, Error> {
+        let last = PressureSample::read()?;
+
+        // there is no earlier reading yet, so start from the 10s average
+        let action = get_action(&limits, last.stall_avg10.unwrap_or(0.0), &last, false);
+
+        Ok(Governor {
+            limits,
+            last,
+            action,
+        })
+    }
+
+    /// Whether the kernel provides pressure stall information, otherwise
+    /// only MemAvailable and the cgroup memory events are taken into account
+    pub fn has_psi(&self) -> bool {
+        self.last.stall_total.is_some()
+    }
+
+    pub fn poll(&mut self) -> Result<Action, Error> {
+        let elapsed = self.last.taken_at.elapsed();
+        if elapsed < MIN_SAMPLE_INTERVAL {
+            return Ok(self.action);
+        }
+
+        let sample = PressureSample::read()?;
+
+        // the share of time stalled since the last reading reacts a lot
+        // faster than the 10s average
+        let stall = match (sample.stall_total, self.last.stall_total) {
+            (Some(total), Some(last)) => {
+                total.saturating_sub(last) as f64 * 100.0 / elapsed.as_micros().max(1) as f64
+            }
+
+            _ => 0.0,
+        };
+
+        let events = sample.cgroup_events > self.last.cgroup_events;
+
+        self.action = get_action(&self.limits, stall, &sample, events);
+        self.last = sample;
+
+        Ok(self.action)
+    }
+}
+
+fn get_action(
+    limits: &PressureLimits,
+    stall: f64,
+    sample: &PressureSample,
+    events: bool,
+) -> Action {
+    if events || sample.available < limits.min_available || stall >= limits.pause {
+        Action::Pause
+    } else if stall >= limits.throttle {
+        Action::Throttle
+    } else {
+        Action::Proceed
+    }
+}
+
+/// What `prefault_adaptive` did
+#[derive(Debug, Default)]
+pub struct AdaptiveReport {
+    pub throttled: Duration,
+    pub paused: Duration,
+
+    /// The files that have not been faulted in, because the pressure did not
+    /// fall in time, or because prefaulting has been interrupted
+    pub skipped: Vec<MappedFile>,
+
+    pub errors: Vec<(PathBuf, MemoryError)>,
+}
+
+/// Fault the files into the page cache in small batches, slow down while
+/// the memory pressure is elevated and pause while it is high. Gives up
+/// after pausing for `max_pause` at a time, or when `running` is cleared.
+pub fn prefault_adaptive(
+    files: &[MappedFile],
+    alignment: Alignment,
+    governor: &mut Governor,
+    max_pause: Duration,
+    workers: &Workers,
+    running: &AtomicBool,
+) -> Result<AdaptiveReport, Error> {
+    let mut report = AdaptiveReport::default();
+
+    let mut index = 0;
+    let mut paused_since: Option<Instant> = None;
+
+    while index < files.len() {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match governor.poll()? {
+            Action::Proceed => {
+                let end = (index + BATCH_SIZE).min(files.len());
+
+                report.errors.extend(memory::prefault_file_mappings(
+                    &files[index..end],
+                    alignment,
+                    workers,
+                ));
+
+                index = end;
+                paused_since = None;
+            }
+
+            Action::Throttle => {
+                report.errors.extend(memory::prefault_file_mappings(
+                    &files[index..=index],
+                    alignment,
+                    workers,
+                ));
+
+                index += 1;
+                paused_since = None;
+
+                thread::sleep(THROTTLE_DELAY);
+                report.throttled += THROTTLE_DELAY;
+            }
+
+            Action::Pause => {
+                let since = *paused_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= max_pause {
+                    break;
+                }
+
+                thread::sleep(PAUSE_INTERVAL);
+                report.paused += PAUSE_INTERVAL;
+            }
+        }
+    }
+
+    report.skipped.extend(files[index..].iter().cloned());
+
+    Ok(report)
+}
@@ -18,14 +18,16 @@
     along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+#![allow(non_local_definitions)]
+
 use failure::{Error, Fail};
-use libc;
 use std::collections::HashSet;
-use std::fs::{read_dir, File};
+use std::fs::{self, read_dir, File};
 use std::io::{self, BufRead, BufReader, Read};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Fail, Debug)]
 pub enum ProcessError {
     #[fail(display = "Could not open process: {}", _0)]
@@ -36,8 +38,20 @@ pub enum ProcessError {
 
     #[fail(display = "Could not enumerate processes")]
     EnumProcessesError(#[fail(cause)] Error),
+
+    #[fail(display = "Could not determine the {} of the process", _0)]
+    MissingInformation(String),
+
+    #[fail(display = "The cgroup v2 hierarchy is not mounted")]
+    NoCgroupHierarchy,
+
+    #[fail(display = "Could not find the cgroup of {}", _0)]
+    CgroupNotFound(String),
 }
 
+/// Where the cgroup v2 hierarchy is mounted, on unified and hybrid systems
+const CGROUP2_MOUNT_POINTS: &[&str] = &["/sys/fs/cgroup", "/sys/fs/cgroup/unified"];
+
 // #[derive(Fail, Debug)]
 // #[fail(display = "Could not parse a mapping")]
 // pub struct ProcessError {}
@@ -47,6 +61,7 @@ pub struct Mapping {
     pub file: Option<PathBuf>,
     pub start: usize,
     pub end: usize,
+    pub offset: u64,
 
     pub read: bool,
     pub write: bool,
@@ -72,6 +87,8 @@ impl FromStr for Mapping {
         let start: usize = usize::from_str_radix(adresses[0], 16).unwrap();
         let end: usize = usize::from_str_radix(adresses[1], 16).unwrap();
 
+        let offset: u64 = u64::from_str_radix(comp[2], 16).unwrap();
+
         let flags = comp[1];
         let read = flags.contains('r');
         let write = flags.contains('w');
@@ -83,6 +100,7 @@ impl FromStr for Mapping {
             file,
             start,
             end,
+            offset,
             read,
             write,
             exec,
@@ -100,37 +118,42 @@ impl std::iter::Iterator for ProcessIterator {
     type Item = Process;
 
     fn next(&mut self) -> Option<Process> {
-        // find the next valid `proc` directory entry
-        let path = 'LOOP: loop {
-            let entry = self.cur.next();
-            if entry.is_none() {
-                break 'LOOP None; // maybe end of `ReadDir` iterator
-            }
+        loop {
+            // find the next valid `proc` directory entry
+            let path = 'LOOP: loop {
+                let entry = self.cur.next();
+                if entry.is_none() {
+                    break 'LOOP None; // maybe end of `ReadDir` iterator
+                }
 
-            let entry = entry.unwrap().unwrap();
-            let path = entry.path();
-
-            let path_str = path.to_string_lossy();
-            if path_str == "/proc/self"
-                || path_str == "/proc/thread-self"
-                || path_str == ".."
-                || path_str == "."
-                || !Path::join(&path, "comm").exists()
-            {
-                continue; // we found a known `bad` directory, skip it
-            } else {
-                break 'LOOP Some(path); // we found the next valid task directory
-            }
-        };
+                let entry = entry.unwrap().unwrap();
+                let path = entry.path();
+
+                let path_str = path.to_string_lossy();
+                if path_str == "/proc/self"
+                    || path_str == "/proc/thread-self"
+                    || path_str == ".."
+                    || path_str == "."
+                    || !Path::join(&path, "comm").exists()
+                {
+                    continue; // we found a known `bad` directory, skip it
+                } else {
+                    break 'LOOP Some(path); // we found the next valid task directory
+                }
+            };
 
-        if let Some(path) = path {
-            let path = path.to_string_lossy();
-            let pid_str = path.trim_matches(|c| !char::is_numeric(c));
-            let pid: libc::pid_t = pid_str.parse::<libc::pid_t>().unwrap();
+            if let Some(path) = path {
+                let path = path.to_string_lossy();
+                let pid_str = path.trim_matches(|c| !char::is_numeric(c));
+                let pid: libc::pid_t = pid_str.parse::<libc::pid_t>().unwrap();
 
-            Process::new(pid).ok()
-        } else {
-            None // end of iteration
+                // skip processes that exited meanwhile, or that we may not inspect
+                if let Ok(process) = Process::new(pid) {
+                    return Some(process);
+                }
+            } else {
+                return None; // end of iteration
+            }
         }
     }
 }
@@ -166,29 +189,130 @@ impl Process {
 
     pub fn enumerate() -> Result<ProcessIterator, Error> {
         Ok(read_dir("/proc/")
-            .and_then(|entry| Ok(ProcessIterator { cur: entry }))
+            .map(|entry| ProcessIterator { cur: entry })
             .map_err(|e| ProcessError::EnumProcessesError(e.into()))?)
     }
 
     pub fn get_command(&self) -> Result<String, Error> {
-        let path = PathBuf::from(format!("/proc/{}/comm", self.pid));
+        read_command(self.pid)
+    }
 
-        match File::open(path) {
-            Ok(file) => {
-                let mut f = BufReader::new(file);
+    /// The path of the executable, from `/proc/<pid>/exe`
+    pub fn get_exe(&self) -> Result<PathBuf, Error> {
+        fs::read_link(format!("/proc/{}/exe", self.pid))
+            .map_err(|e| ProcessError::ReadError(e).into())
+    }
 
-                let mut comm = String::new();
-                f.read_to_string(&mut comm)?;
+    /// The full command line, from `/proc/<pid>/cmdline`
+    pub fn get_cmdline(&self) -> Result<Vec<String>, Error> {
+        let cmdline =
+            fs::read(format!("/proc/{}/cmdline", self.pid)).map_err(ProcessError::ReadError)?;
 
-                comm = comm.trim().into();
+        Ok(cmdline
+            .split(|b| *b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect())
+    }
+
+    /// The path of the process in the unified (v2) cgroup hierarchy, or the
+    /// first controller hierarchy on systems without it
+    pub fn get_cgroup(&self) -> Result<String, Error> {
+        let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", self.pid))
+            .map_err(ProcessError::ReadError)?;
+
+        let unified = cgroups.lines().find(|l| l.starts_with("0::"));
+
+        unified
+            .or_else(|| cgroups.lines().next())
+            .and_then(|l| l.splitn(3, ':').nth(2))
+            .map(|path| path.to_string())
+            .ok_or_else(|| ProcessError::MissingInformation("cgroup".into()).into())
+    }
+
+    /// The systemd unit the process belongs to, like `nginx.service` or a
+    /// `.scope` unit of a desktop application
+    pub fn get_unit(&self) -> Result<String, Error> {
+        let cgroup = self.get_cgroup()?;
+
+        cgroup
+            .rsplit('/')
+            .find(|c| c.ends_with(".service") || c.ends_with(".scope"))
+            .map(|unit| unit.to_string())
+            .ok_or_else(|| ProcessError::MissingInformation("unit".into()).into())
+    }
+
+    /// The real user ID
+    pub fn get_uid(&self) -> Result<libc::uid_t, Error> {
+        self.get_status_field("Uid:")
+            .and_then(|uid| uid.split_whitespace().next().map(|u| u.to_string()))
+            .and_then(|uid| uid.parse().ok())
+            .ok_or_else(|| ProcessError::MissingInformation("user ID".into()).into())
+    }
 
-                let v: Vec<&str> = comm.split('\u{0}').collect();
+    pub fn get_parent_pid(&self) -> Result<libc::pid_t, Error> {
+        self.get_status_field("PPid:")
+            .and_then(|ppid| ppid.trim().parse().ok())
+            .ok_or_else(|| ProcessError::MissingInformation("parent process".into()).into())
+    }
+
+    /// The PIDs of the child processes of all threads of the process
+    pub fn get_children(&self) -> Result<Vec<libc::pid_t>, Error> {
+        let mut result = vec![];
+
+        for entry in
+            read_dir(format!("/proc/{}/task", self.pid)).map_err(ProcessError::ReadError)?
+        {
+            let children = match fs::read_to_string(entry?.path().join("children")) {
+                Ok(children) => children,
+
+                // the thread exited meanwhile
+                Err(_) => continue,
+            };
+
+            result.extend(
+                children
+                    .split_whitespace()
+                    .filter_map(|pid| pid.parse::<libc::pid_t>().ok()),
+            );
+        }
+
+        Ok(result)
+    }
 
-                Ok(v[0].to_owned())
+    /// All processes that descend from the process, processes that exited
+    /// meanwhile are skipped
+    pub fn get_descendants(&self) -> Vec<Process> {
+        let mut result = vec![];
+        let mut seen = HashSet::new();
+        let mut pending = self.get_children().unwrap_or_default();
+
+        while let Some(pid) = pending.pop() {
+            if !seen.insert(pid) {
+                continue;
             }
 
-            Err(e) => Err(ProcessError::ReadError(e).into()),
+            if let Ok(process) = Process::new(pid) {
+                pending.extend(process.get_children().unwrap_or_default());
+                result.push(process);
+            }
         }
+
+        result
+    }
+
+    /// The command of the parent process
+    pub fn get_parent_command(&self) -> Result<String, Error> {
+        read_command(self.get_parent_pid()?)
+    }
+
+    fn get_status_field(&self, name: &str) -> Option<String> {
+        let status = fs::read_to_string(format!("/proc/{}/status", self.pid)).ok()?;
+
+        status
+            .lines()
+            .find(|l| l.starts_with(name))
+            .map(|l| l[name.len()..].trim().to_string())
     }
 
     pub fn get_mapped_files(&self) -> HashSet<PathBuf> {
@@ -206,6 +330,102 @@ impl Process {
     }
 }
 
+fn get_cgroup_root() -> Result<PathBuf, Error> {
+    CGROUP2_MOUNT_POINTS
+        .iter()
+        .map(PathBuf::from)
+        .find(|p| p.join("cgroup.controllers").exists())
+        .ok_or_else(|| ProcessError::NoCgroupHierarchy.into())
+}
+
+/// Find the cgroup of a systemd unit, like `/system.slice/nginx.service`
+pub fn find_unit_cgroup(unit: &str) -> Result<String, Error> {
+    let root = get_cgroup_root()?;
+
+    walkdir::WalkDir::new(&root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.file_type().is_dir() && entry.file_name() == unit)
+        .and_then(|entry| {
+            entry
+                .path()
+                .strip_prefix(&root)
+                .ok()
+                .map(|p| format!("/{}", p.to_string_lossy()))
+        })
+        .ok_or_else(|| ProcessError::CgroupNotFound(unit.into()).into())
+}
+
+/// The directory of a cgroup in the unified hierarchy. The cgroup is either
+/// a path in the hierarchy, like `/system.slice/nginx.service`, or the path
+/// of its directory.
+pub fn get_cgroup_dir(cgroup: &str) -> Result<PathBuf, Error> {
+    let root = get_cgroup_root()?;
+
+    let dir = match Path::new(cgroup).strip_prefix(&root) {
+        Ok(relative) => root.join(relative),
+        Err(_) => root.join(cgroup.trim_start_matches('/')),
+    };
+
+    if !dir.join("cgroup.procs").exists() {
+        return Err(ProcessError::CgroupNotFound(cgroup.into()).into());
+    }
+
+    Ok(dir)
+}
+
+/// All processes in a cgroup and its descendant cgroups, from their
+/// `cgroup.procs` files, see `get_cgroup_dir`
+pub fn get_cgroup_processes(cgroup: &str) -> Result<Vec<Process>, Error> {
+    let dir = get_cgroup_dir(cgroup)?;
+
+    let mut result = vec![];
+    for entry in walkdir::WalkDir::new(&dir) {
+        let entry = entry?;
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+
+        let procs = match fs::read_to_string(entry.path().join("cgroup.procs")) {
+            Ok(procs) => procs,
+
+            // the cgroup has been removed meanwhile
+            Err(_) => continue,
+        };
+
+        // skip processes that exited meanwhile
+        result.extend(
+            procs
+                .split_whitespace()
+                .filter_map(|pid| pid.parse::<libc::pid_t>().ok())
+                .filter_map(|pid| Process::new(pid).ok()),
+        );
+    }
+
+    Ok(result)
+}
+
+fn read_command(pid: libc::pid_t) -> Result<String, Error> {
+    let path = PathBuf::from(format!("/proc/{}/comm", pid));
+
+    match File::open(path) {
+        Ok(file) => {
+            let mut f = BufReader::new(file);
+
+            let mut comm = String::new();
+            f.read_to_string(&mut comm)?;
+
+            comm = comm.trim().into();
+
+            let v: Vec<&str> = comm.split('\u{0}').collect();
+
+            Ok(v[0].to_owned())
+        }
+
+        Err(e) => Err(ProcessError::ReadError(e).into()),
+    }
+}
+
 fn is_section_mapping<T: AsRef<Path>>(mapping: T) -> bool {
     let mapping = mapping.as_ref().to_str().unwrap();
 
@@ -229,6 +449,11 @@ fn is_section_mapping<T: AsRef<Path>>(mapping: T) -> bool {
         return true;
     }
 
+    // other special mappings, like `[vvar_vclock]` on newer kernels
+    if mapping.starts_with('[') && mapping.ends_with(']') {
+        return true;
+    }
+
     if mapping.contains("(deleted)") {
         return true;
     }
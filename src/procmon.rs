@@ -0,0 +1,225 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+#![allow(non_local_definitions)]
+
+use failure::{Error, Fail};
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+// see: <linux/connector.h> and <linux/cn_proc.h>
+const NETLINK_CONNECTOR: libc::c_int = 11;
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_CN_MCAST_IGNORE: u32 = 2;
+
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+const NLMSG_HDR_LEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+
+/// Offset of `event_data` in `struct proc_event`
+const PROC_EVENT_DATA_OFFSET: usize = 16;
+
+#[derive(Fail, Debug)]
+pub enum ProcMonitorError {
+    #[fail(display = "Could not connect to the process events connector: {}", _0)]
+    Connect(#[fail(cause)] io::Error),
+
+    #[fail(display = "Could not receive process events: {}", _0)]
+    Receive(#[fail(cause)] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcEvent {
+    Exec { pid: libc::pid_t },
+    Exit { pid: libc::pid_t },
+}
+
+/// Receives process lifecycle events from the kernel, via the netlink
+/// process events connector. Requires CAP_NET_ADMIN.
+pub struct ProcMonitor {
+    fd: RawFd,
+}
+
+impl ProcMonitor {
+    pub fn new() -> Result<Self, Error> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(ProcMonitorError::Connect(io::Error::last_os_error()).into());
+        }
+
+        let monitor = ProcMonitor { fd };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = CN_IDX_PROC;
+        addr.nl_pid = unsafe { libc::getpid() } as u32;
+
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(ProcMonitorError::Connect(io::Error::last_os_error()).into());
+        }
+
+        monitor
+            .send_op(PROC_CN_MCAST_LISTEN)
+            .map_err(ProcMonitorError::Connect)?;
+
+        Ok(monitor)
+    }
+
+    fn send_op(&self, op: u32) -> io::Result<()> {
+        let len = NLMSG_HDR_LEN + CN_MSG_LEN + mem::size_of::<u32>();
+        let mut buf = Vec::with_capacity(len);
+
+        // struct nlmsghdr
+        buf.extend_from_slice(&(len as u32).to_ne_bytes());
+        buf.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        buf.extend_from_slice(&0u16.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&(unsafe { libc::getpid() } as u32).to_ne_bytes());
+
+        // struct cn_msg
+        buf.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        buf.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&(mem::size_of::<u32>() as u16).to_ne_bytes());
+        buf.extend_from_slice(&0u16.to_ne_bytes());
+
+        // enum proc_cn_mcast_op
+        buf.extend_from_slice(&op.to_ne_bytes());
+
+        let result = unsafe { libc::send(self.fd, buf.as_ptr() as *const libc::c_void, len, 0) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for process events
+    pub fn read_events(&mut self, timeout: Duration) -> Result<Vec<ProcEvent>, Error> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let result = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+        if result < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(vec![]);
+            }
+
+            return Err(ProcMonitorError::Receive(error).into());
+        } else if result == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut buf = [0u8; 4096];
+        let len = unsafe {
+            libc::recv(
+                self.fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if len < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::WouldBlock
+                || error.kind() == io::ErrorKind::Interrupted
+            {
+                return Ok(vec![]);
+            }
+
+            return Err(ProcMonitorError::Receive(error).into());
+        }
+
+        Ok(parse_events(&buf[..len as usize]))
+    }
+}
+
+impl Drop for ProcMonitor {
+    fn drop(&mut self) {
+        let _ = self.send_op(PROC_CN_MCAST_IGNORE);
+
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn parse_events(buf: &[u8]) -> Vec<ProcEvent> {
+    let mut result = vec![];
+
+    let mut offset = 0;
+    while let Some(msg_len) = read_u32(buf, offset) {
+        let msg_len = msg_len as usize;
+        if msg_len < NLMSG_HDR_LEN || offset + msg_len > buf.len() {
+            break;
+        }
+
+        let event = offset + NLMSG_HDR_LEN + CN_MSG_LEN;
+        let what = read_u32(buf, event);
+        let pid = read_u32(buf, event + PROC_EVENT_DATA_OFFSET).map(|p| p as libc::pid_t);
+        let tgid = read_u32(buf, event + PROC_EVENT_DATA_OFFSET + 4).map(|p| p as libc::pid_t);
+
+        match (what, pid, tgid) {
+            (Some(PROC_EVENT_EXEC), Some(pid), _) => result.push(ProcEvent::Exec { pid }),
+
+            // only report the exit of the whole thread group
+            (Some(PROC_EVENT_EXIT), Some(pid), Some(tgid)) if pid == tgid => {
+                result.push(ProcEvent::Exit { pid })
+            }
+
+            _ => {}
+        }
+
+        // netlink messages are aligned to 4 bytes
+        offset += (msg_len + 3) & !3;
+    }
+
+    result
+}
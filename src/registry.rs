@@ -0,0 +1,199 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::memory::{self, Alignment, LockedRegion, MemoryError};
+use crate::workset::*;
+
+/// Keeps track of the regions locked by `prefault mlock`, per snapshot or
+/// static file list, so that they can be released again
+pub struct LockRegistry {
+    alignment: Alignment,
+    sources: BTreeMap<PathBuf, (Source, Vec<LockedRegion>)>,
+}
+
+/// What `LockRegistry::sync` changed
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// The names of the sources that have been locked and unlocked
+    pub locked: Vec<String>,
+    pub unlocked: Vec<String>,
+
+    pub errors: Vec<(PathBuf, MemoryError)>,
+}
+
+impl LockRegistry {
+    pub fn new(alignment: Alignment) -> Self {
+        LockRegistry {
+            alignment,
+            sources: BTreeMap::new(),
+        }
+    }
+
+    /// Unlock sources that are no longer in the work set or whose files
+    /// changed, and lock the ones that are not locked yet, in priority order
+    pub fn sync(&mut self, workset: &WorkSet) -> SyncReport {
+        let mut report = SyncReport::default();
+
+        let wanted: BTreeMap<&PathBuf, &Source> =
+            workset.sources.iter().map(|s| (&s.path, s)).collect();
+
+        let stale: Vec<PathBuf> = self
+            .sources
+            .iter()
+            .filter(|(path, (source, _))| wanted.get(path) != Some(&source))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        // unlock first, to make room in the budget
+        for path in stale.iter() {
+            if let Some((source, regions)) = self.sources.remove(path) {
+                report.errors.extend(memory::munlock_regions(&regions));
+                report.unlocked.push(source.name);
+            }
+        }
+
+        for source in workset.sources.iter() {
+            if self.sources.contains_key(&source.path) {
+                continue;
+            }
+
+            let (regions, errors) =
+                memory::mlock_file_mappings(&source.files, self.alignment, &source.path);
+            self.sources
+                .insert(source.path.clone(), (source.clone(), regions));
+
+            report.errors.extend(errors);
+            report.locked.push(source.name.clone());
+        }
+
+        report
+    }
+
+    /// Unlock all sources, returns the errors by file
+    pub fn unlock_all(&mut self) -> Vec<(PathBuf, MemoryError)> {
+        let mut result = vec![];
+
+        for (_, (_, regions)) in self.sources.iter() {
+            result.extend(memory::munlock_regions(regions));
+        }
+
+        self.sources.clear();
+
+        result
+    }
+
+    pub fn get_status(&self) -> Status {
+        Status {
+            pid: unsafe { libc::getpid() },
+            updated_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            sources: self
+                .sources
+                .iter()
+                .map(|(path, (source, regions))| SourceStatus {
+                    path: path.clone(),
+                    name: source.name.clone(),
+                    regions: regions
+                        .iter()
+                        .map(|r| RegionStatus {
+                            path: r.path.clone(),
+                            addr: r.addr as u64,
+                            length: r.length as u64,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Drop for LockRegistry {
+    fn drop(&mut self) {
+        let _ = self.unlock_all();
+    }
+}
+
+/// What a running `prefault mlock` has locked, as published in the status file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub pid: libc::pid_t,
+    pub updated_at: u64,
+
+    #[serde(rename = "source", default)]
+    pub sources: Vec<SourceStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SourceStatus {
+    pub path: PathBuf,
+    pub name: String,
+
+    #[serde(rename = "region", default)]
+    pub regions: Vec<RegionStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegionStatus {
+    pub path: PathBuf,
+    pub addr: u64,
+    pub length: u64,
+}
+
+impl SourceStatus {
+    pub fn get_locked_size(&self) -> u64 {
+        self.regions.iter().map(|r| r.length).sum()
+    }
+}
+
+impl Status {
+    pub fn new_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let text = fs::read_to_string(path.as_ref())?;
+
+        Ok(toml::from_str(&text)?)
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // replace the file atomically, `prefault status` may be reading it
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, toml::to_string(self)?)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    pub fn get_locked_size(&self) -> u64 {
+        self.sources.iter().map(|s| s.get_locked_size()).sum()
+    }
+}
@@ -0,0 +1,462 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+#![allow(non_local_definitions)]
+
+use failure::{Error, Fail};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::memory::MemoryLimit;
+use crate::pressure::PressureLimits;
+use crate::snapshot::Identity;
+use crate::store::SnapshotStore;
+use crate::util;
+use crate::watch::WatchLimits;
+use crate::workers::IoLimits;
+
+const DEFAULT_CONFIG_FILE: &str = "/etc/prefault/prefault.conf";
+
+/// All configuration keys, each may be overridden by an environment variable
+/// named like the key in upper case, prefixed with `PREFAULT_`
+const KEYS: &[&str] = &[
+    "snapshot_dir",
+    "static_filelist_dir",
+    "status_file",
+    "user_snapshot_dir",
+    "user_status_file",
+    "max_locked_memory",
+    "max_cached_memory",
+    "pressure_throttle",
+    "pressure_pause",
+    "min_available_memory",
+    "watch_interval",
+    "watch_threshold",
+    "watch_io_budget",
+    "io_priority",
+    "nice",
+    "max_read_rate",
+    "max_concurrency",
+    "io_order",
+    "snapshot_identity",
+    "identity_argv_pattern",
+];
+
+#[derive(Fail, Debug)]
+pub enum SettingsError {
+    #[fail(display = "Invalid value for {}: {}", key, msg)]
+    InvalidValue { key: String, msg: String },
+
+    #[fail(display = "{}: {}", path, msg)]
+    InvalidFile { path: String, msg: String },
+}
+
+/// Where the value of a configuration key came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueSource {
+    Default,
+    File(PathBuf),
+    Environment(String),
+}
+
+impl fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValueSource::Default => write!(f, "default"),
+            ValueSource::File(path) => write!(f, "{}", path.display()),
+            ValueSource::Environment(var) => write!(f, "environment variable {}", var),
+        }
+    }
+}
+
+/// The value of a configuration key as it has been read, before parsing
+#[derive(Debug, Clone)]
+pub struct RawValue {
+    pub value: String,
+    pub source: ValueSource,
+}
+
+/// The effective configuration, merged from the built-in defaults, the
+/// configuration file, its drop-in directory and the environment
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub snapshot_dir: PathBuf,
+    pub static_filelist_dir: PathBuf,
+
+    /// The snapshot store of an unprivileged user, layered on `snapshot_dir`
+    pub user_snapshot_dir: Option<PathBuf>,
+
+    /// Where a running `prefault mlock` publishes its locked regions
+    pub status_file: PathBuf,
+
+    /// Upper bounds for the memory used by `prefault mlock` and `prefault cache`
+    pub max_locked_memory: Option<MemoryLimit>,
+    pub max_cached_memory: Option<MemoryLimit>,
+
+    /// When `prefault cache --adaptive` slows down and pauses
+    pub pressure_limits: PressureLimits,
+
+    /// When and how much `prefault cache --watch` faults in again
+    pub watch_limits: WatchLimits,
+
+    /// How the files are read while prefaulting
+    pub io_limits: IoLimits,
+
+    /// What snapshots of processes are keyed by
+    pub identity: Identity,
+
+    /// All values by key, including unknown keys, for `prefault config dump`
+    pub values: BTreeMap<String, RawValue>,
+}
+
+impl Settings {
+    /// Merge, in this order, the built-in defaults, the configuration file,
+    /// the drop-ins in `<config file>.d/*.toml` in lexical order, and the
+    /// `PREFAULT_*` environment variables. A missing configuration file is
+    /// only an error if it has been specified explicitly.
+    pub fn load<P: AsRef<Path>>(config_file: Option<P>) -> Result<Self, Error> {
+        let explicit = config_file.is_some();
+        let config_file = config_file
+            .map(|p| p.as_ref().to_path_buf())
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE));
+
+        let mut values = get_defaults();
+
+        if explicit || config_file.exists() {
+            merge_file(&mut values, &config_file)?;
+        }
+
+        let drop_in_dir = PathBuf::from(format!("{}.d", config_file.display()));
+        if let Ok(entries) = fs::read_dir(&drop_in_dir) {
+            let mut drop_ins: Vec<PathBuf> = entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension() == Some(OsStr::new("toml")))
+                .collect();
+            drop_ins.sort();
+
+            for drop_in in drop_ins.iter() {
+                merge_file(&mut values, drop_in)?;
+            }
+        }
+
+        for key in KEYS.iter() {
+            let var = format!("PREFAULT_{}", key.to_uppercase());
+
+            if let Ok(value) = env::var(&var) {
+                values.insert(
+                    key.to_string(),
+                    RawValue {
+                        value,
+                        source: ValueSource::Environment(var),
+                    },
+                );
+            }
+        }
+
+        Self::new_from_values(values)
+    }
+
+    fn new_from_values(values: BTreeMap<String, RawValue>) -> Result<Self, Error> {
+        let get = |key: &str| values.get(key).map(|v| v.value.clone());
+
+        let get_path = |key: &str| expand_home_dir(PathBuf::from(get(key).unwrap_or_default()));
+
+        let get_limit = |key: &str| -> Result<Option<MemoryLimit>, Error> {
+            match get(key) {
+                Some(value) => value.parse().map(Some).map_err(|msg| {
+                    SettingsError::InvalidValue {
+                        key: key.into(),
+                        msg,
+                    }
+                    .into()
+                }),
+
+                None => Ok(None),
+            }
+        };
+
+        let get_percentage = |key: &str| -> Result<f64, Error> {
+            let value = get(key).unwrap_or_default();
+
+            match value.trim().trim_end_matches('%').parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+
+                _ => Err(SettingsError::InvalidValue {
+                    key: key.into(),
+                    msg: format!("Invalid percentage '{}', expected a number like 10%", value),
+                }
+                .into()),
+            }
+        };
+
+        let pressure_limits = PressureLimits {
+            throttle: get_percentage("pressure_throttle")?,
+            pause: get_percentage("pressure_pause")?,
+            min_available: get_limit("min_available_memory")?
+                .map(|l| l.get_bytes())
+                .unwrap_or(0),
+        };
+
+        let interval = get("watch_interval").unwrap_or_default();
+        let interval = match util::parse_duration(interval.trim()) {
+            Some(seconds) if seconds > 0 => Duration::from_secs(seconds as u64),
+
+            _ => {
+                return Err(SettingsError::InvalidValue {
+                    key: "watch_interval".into(),
+                    msg: format!(
+                        "Invalid interval '{}', expected a duration like 5m",
+                        interval
+                    ),
+                }
+                .into())
+            }
+        };
+
+        let watch_limits = WatchLimits {
+            interval,
+            threshold: get_percentage("watch_threshold")?,
+            io_budget: get_limit("watch_io_budget")?.map(|l| l.get_bytes()),
+        };
+
+        let invalid = |key: &str, msg: String| -> Error {
+            SettingsError::InvalidValue {
+                key: key.into(),
+                msg,
+            }
+            .into()
+        };
+
+        let io_priority = match get("io_priority") {
+            Some(value) => Some(value.parse().map_err(|msg| invalid("io_priority", msg))?),
+            None => None,
+        };
+
+        let nice = match get("nice") {
+            Some(value) => match value.trim().parse::<i32>() {
+                Ok(nice) if (-20..=19).contains(&nice) => Some(nice),
+
+                _ => {
+                    return Err(invalid(
+                        "nice",
+                        format!("Invalid nice value '{}', expected -20 to 19", value),
+                    ))
+                }
+            },
+
+            None => None,
+        };
+
+        let max_read_rate = match get("max_read_rate") {
+            Some(value) => {
+                let rate = value.trim();
+                let rate = rate.strip_suffix("/s").unwrap_or(rate);
+
+                match util::parse_size(rate) {
+                    Some(rate) if rate > 0 => Some(rate),
+
+                    _ => {
+                        return Err(invalid(
+                            "max_read_rate",
+                            format!(
+                                "Invalid rate '{}', expected a size per second like 20M",
+                                value
+                            ),
+                        ))
+                    }
+                }
+            }
+
+            None => None,
+        };
+
+        let max_concurrency = match get("max_concurrency") {
+            Some(value) => match value.trim().parse::<usize>() {
+                Ok(threads) if threads > 0 => Some(threads),
+
+                _ => {
+                    return Err(invalid(
+                        "max_concurrency",
+                        format!("Invalid number of threads '{}'", value),
+                    ))
+                }
+            },
+
+            None => None,
+        };
+
+        let io_order = get("io_order")
+            .unwrap_or_default()
+            .parse()
+            .map_err(|msg| invalid("io_order", msg))?;
+
+        let io_limits = IoLimits {
+            io_priority,
+            nice,
+            max_read_rate,
+            max_concurrency,
+            io_order,
+        };
+
+        let identity = Identity::new(
+            &get("snapshot_identity").unwrap_or_default(),
+            &get("identity_argv_pattern").unwrap_or_default(),
+        )
+        .map_err(|msg| SettingsError::InvalidValue {
+            key: "snapshot_identity".into(),
+            msg,
+        })?;
+
+        // unprivileged users keep their own snapshots and status file
+        let unprivileged = unsafe { libc::geteuid() } != 0;
+
+        let (user_snapshot_dir, status_file) = if unprivileged {
+            (
+                Some(get_path("user_snapshot_dir")),
+                get_path("user_status_file"),
+            )
+        } else {
+            (None, get_path("status_file"))
+        };
+
+        Ok(Settings {
+            snapshot_dir: get_path("snapshot_dir"),
+            static_filelist_dir: get_path("static_filelist_dir"),
+            user_snapshot_dir,
+            status_file,
+            max_locked_memory: get_limit("max_locked_memory")?,
+            max_cached_memory: get_limit("max_cached_memory")?,
+            pressure_limits,
+            watch_limits,
+            io_limits,
+            identity,
+            values,
+        })
+    }
+
+    pub fn get_snapshot_store(&self) -> SnapshotStore {
+        SnapshotStore::new(&self.snapshot_dir, self.user_snapshot_dir.as_ref())
+    }
+
+    pub fn is_known_key(key: &str) -> bool {
+        KEYS.contains(&key)
+    }
+}
+
+fn get_defaults() -> BTreeMap<String, RawValue> {
+    let runtime_dir = match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(format!("/run/user/{}", unsafe { libc::geteuid() })),
+    };
+
+    let defaults = vec![
+        ("snapshot_dir", "/var/lib/prefault/snapshots".to_string()),
+        ("static_filelist_dir", "/etc/prefault/cache.d".to_string()),
+        ("status_file", "/run/prefault/status".to_string()),
+        (
+            "user_snapshot_dir",
+            get_xdg_dir("XDG_DATA_HOME", "~/.local/share")
+                .join("prefault/snapshots")
+                .to_string_lossy()
+                .to_string(),
+        ),
+        (
+            "user_status_file",
+            runtime_dir
+                .join("prefault/status")
+                .to_string_lossy()
+                .to_string(),
+        ),
+        ("pressure_throttle", "10%".to_string()),
+        ("pressure_pause", "40%".to_string()),
+        ("min_available_memory", "5%".to_string()),
+        ("watch_interval", "5m".to_string()),
+        ("watch_threshold", "90%".to_string()),
+        ("watch_io_budget", "256M".to_string()),
+        ("io_order", "physical".to_string()),
+        ("snapshot_identity", "comm".to_string()),
+        ("identity_argv_pattern", "^[^-]".to_string()),
+    ];
+
+    defaults
+        .into_iter()
+        .map(|(key, value)| {
+            (
+                key.to_string(),
+                RawValue {
+                    value,
+                    source: ValueSource::Default,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Read a TOML file and let its values override the ones in `values`
+fn merge_file(values: &mut BTreeMap<String, RawValue>, path: &Path) -> Result<(), Error> {
+    let invalid = |msg: String| SettingsError::InvalidFile {
+        path: path.to_string_lossy().into(),
+        msg,
+    };
+
+    let text = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let table: toml::value::Table = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+
+    for (key, value) in table.into_iter() {
+        let value = match value {
+            toml::Value::String(s) => s,
+            other => other.to_string(),
+        };
+
+        values.insert(
+            key,
+            RawValue {
+                value,
+                source: ValueSource::File(path.to_path_buf()),
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// An XDG base directory from the environment, or its default
+fn get_xdg_dir(var: &str, default: &str) -> PathBuf {
+    match env::var(var) {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => expand_home_dir(PathBuf::from(default)),
+    }
+}
+
+fn expand_home_dir(path: PathBuf) -> PathBuf {
+    if path.starts_with("~/") {
+        let home_dir = PathBuf::from(env::var("HOME").unwrap_or_else(|_| "/root".into()));
+
+        home_dir.join(path.strip_prefix(Path::new("~/")).unwrap())
+    } else {
+        path
+    }
+}
@@ -18,108 +18,918 @@
     along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+#![allow(non_local_definitions)]
+
 use failure::{Error, Fail};
-use std::collections::HashSet;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::BufRead;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::elf::*;
+use crate::memory;
 use crate::process::*;
+use crate::trace::*;
 use crate::util::*;
 
+/// The version of the snapshot file format written by `save_to_file()`
+pub const SNAPSHOT_VERSION: &str = "2.0";
+
+/// Size of the kernel's process name buffer, including the terminating NUL
+const TASK_COMM_LEN: usize = 16;
+
 #[derive(Fail, Debug)]
 pub enum SnapshotError {
     #[fail(
-        display = "Invalid snapshot file format or unsupported version: {}",
-        _0
+        display = "Invalid snapshot file format or unsupported version '{}': {}",
+        version, path
     )]
-    FormatError(String),
+    FormatError { path: String, version: String },
+
+    #[fail(display = "Could not find the executable of snapshot: {}", _0)]
+    NoExecutable(String),
+
+    #[fail(display = "No processes to take a snapshot of")]
+    NoProcesses,
+
+    #[fail(display = "Not renamed, {} already exists", _0)]
+    AlreadyExists(String),
+}
+
+/// What snapshots are keyed by, processes with the same key share a snapshot
+#[derive(Debug, Clone)]
+pub enum Identity {
+    /// The process name, as truncated by the kernel
+    Comm,
+
+    /// The path of the executable
+    Exe,
+
+    /// The path of the executable, and the arguments matching the pattern
+    ExeArgv(Regex),
+
+    /// The systemd unit
+    Unit,
+}
+
+impl Identity {
+    pub fn new(kind: &str, argv_pattern: &str) -> Result<Self, String> {
+        match kind {
+            "comm" => Ok(Identity::Comm),
+            "exe" => Ok(Identity::Exe),
+            "exe+argv" => Regex::new(argv_pattern)
+                .map(Identity::ExeArgv)
+                .map_err(|e| e.to_string()),
+            "unit" => Ok(Identity::Unit),
+
+            _ => Err(format!(
+                "Invalid identity '{}', expected one of: comm, exe, exe+argv, unit",
+                kind
+            )),
+        }
+    }
+
+    /// Falls back to the command if the information is not available
+    fn get_key(
+        &self,
+        command: &str,
+        exe: Option<&Path>,
+        cmdline: Option<&[String]>,
+        unit: Option<&str>,
+    ) -> String {
+        match (self, exe, unit) {
+            (Identity::Exe, Some(exe), _) => exe.to_string_lossy().to_string(),
+
+            (Identity::ExeArgv(pattern), Some(exe), _) => {
+                let mut key = exe.to_string_lossy().to_string();
+
+                for arg in cmdline.unwrap_or_default().iter().skip(1) {
+                    if pattern.is_match(arg) {
+                        key.push(' ');
+                        key.push_str(arg);
+                    }
+                }
+
+                key
+            }
+
+            (Identity::Unit, _, Some(unit)) => unit.to_string(),
+
+            _ => command.to_string(),
+        }
+    }
+
+    /// Get the key of the snapshot of a running process
+    pub fn get_process_key(&self, process: &Process) -> Result<String, Error> {
+        let command = process.get_command()?;
+
+        Ok(self.get_key(
+            &command,
+            process.get_exe().ok().as_deref(),
+            process.get_cmdline().ok().as_deref(),
+            process.get_unit().ok().as_deref(),
+        ))
+    }
+}
+
+/// Get the path of the snapshot file of `command` in `snapshot_dir`
+pub fn get_snapshot_path<P: AsRef<Path>, T: AsRef<str>>(snapshot_dir: P, command: T) -> PathBuf {
+    snapshot_dir
+        .as_ref()
+        .join(format!("{}.snapshot", hash_string(command.as_ref())))
+}
+
+/// The old and new paths of the renamed snapshots, and the snapshots that
+/// could not be renamed
+pub type Migration = (Vec<(PathBuf, PathBuf)>, Vec<(PathBuf, Error)>);
+
+/// Rename snapshot files that are still named after the hash of an earlier
+/// version of prefault
+pub fn migrate_snapshots<P: AsRef<Path>>(snapshot_dir: P) -> Result<Migration, Error> {
+    let mut renamed = vec![];
+    let mut failed = vec![];
+
+    for entry in fs::read_dir(snapshot_dir.as_ref())? {
+        let path = entry?.path();
+
+        let stem = match path.file_stem() {
+            Some(stem) if path.extension() == Some(OsStr::new("snapshot")) => {
+                stem.to_string_lossy()
+            }
+
+            _ => continue,
+        };
+
+        // earlier versions used decimal numbers, only names that consist of
+        // digits alone have to be checked against the key of the snapshot
+        if is_hash_string(&stem) && !stem.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+
+        let snapshot = match Snapshot::new_from_file(&path) {
+            Ok(snapshot) => snapshot,
+
+            Err(e) => {
+                failed.push((path, e));
+                continue;
+            }
+        };
+
+        let new_path = get_snapshot_path(snapshot_dir.as_ref(), snapshot.get_key());
+        if new_path == path {
+            continue;
+        }
+
+        if new_path.exists() {
+            let e = SnapshotError::AlreadyExists(new_path.to_string_lossy().into());
+            failed.push((path, e.into()));
+            continue;
+        }
+
+        fs::rename(&path, &new_path)?;
+        renamed.push((path, new_path));
+    }
+
+    Ok((renamed, failed))
+}
+
+/// A byte range of a file, as it was mapped into the address space of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(from = "(u64, u64)", into = "(u64, u64)")]
+pub struct MappedRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl From<(u64, u64)> for MappedRange {
+    fn from((offset, length): (u64, u64)) -> Self {
+        MappedRange { offset, length }
+    }
+}
+
+impl From<MappedRange> for (u64, u64) {
+    fn from(range: MappedRange) -> Self {
+        (range.offset, range.length)
+    }
+}
+
+impl MappedRange {
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// Sort `ranges` and merge overlapping or adjacent ranges
+pub fn coalesce_ranges(ranges: &mut Vec<MappedRange>) {
+    ranges.sort();
+
+    let mut result: Vec<MappedRange> = Vec::with_capacity(ranges.len());
+    for range in ranges.drain(..) {
+        match result.last_mut() {
+            Some(last) if range.offset <= last.end() => {
+                last.length = last.length.max(range.end() - last.offset);
+            }
+
+            _ => result.push(range),
+        }
+    }
+
+    *ranges = result;
+}
+
+/// A run of consecutive pages resident in the page cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(from = "(u64, u64)", into = "(u64, u64)")]
+pub struct PageRun {
+    pub first: u64,
+    pub count: u64,
+}
+
+impl From<(u64, u64)> for PageRun {
+    fn from((first, count): (u64, u64)) -> Self {
+        PageRun { first, count }
+    }
+}
+
+impl From<PageRun> for (u64, u64) {
+    fn from(run: PageRun) -> Self {
+        (run.first, run.count)
+    }
+}
+
+impl PageRun {
+    pub fn to_range(self, page_size: u64) -> MappedRange {
+        MappedRange {
+            offset: self.first * page_size,
+            length: self.count * page_size,
+        }
+    }
+}
+
+/// A file referenced by a snapshot, along with the metadata it had at capture time
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MappedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: i64,
+    pub inode: u64,
+    pub device: u64,
+
+    /// The mapped byte ranges; empty means the whole file
+    #[serde(default)]
+    pub ranges: Vec<MappedRange>,
+
+    /// Run-length encoded page cache residency, if it has been recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resident: Option<Vec<PageRun>>,
+
+    /// In how many captures the file has been seen, and when it has been seen
+    /// last, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hits: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<u64>,
+
+    /// The number of merged captures since the file has been seen last
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub missed: u32,
+
+    /// The commands of the processes that mapped the file, recorded for
+    /// snapshots of process trees
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub processes: BTreeSet<String>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+impl MappedFile {
+    pub fn new<T: AsRef<Path>>(path: T, mut ranges: Vec<MappedRange>) -> Self {
+        coalesce_ranges(&mut ranges);
+
+        let mut result = MappedFile {
+            path: path.as_ref().to_path_buf(),
+            ranges,
+            ..Default::default()
+        };
+
+        if let Ok(metadata) = fs::metadata(path.as_ref()) {
+            result.size = metadata.len();
+            result.mtime = metadata.mtime();
+            result.inode = metadata.ino();
+            result.device = metadata.dev();
+        }
+
+        result
+    }
+}
+
+/// The ways in which a file may differ from the state recorded in a snapshot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileState {
+    Missing,
+    Changed,
+}
+
+impl fmt::Display for FileState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileState::Missing => write!(f, "missing"),
+            FileState::Changed => write!(f, "changed"),
+        }
+    }
+}
+
+impl MappedFile {
+    fn is_whole_file(&self) -> bool {
+        self.ranges.is_empty() && self.resident.is_none()
+    }
+
+    /// The byte ranges that should be faulted in, recorded residency takes
+    /// precedence over the mapped ranges
+    pub fn get_wanted_ranges(&self, page_size: u64) -> Vec<MappedRange> {
+        match self.resident {
+            Some(ref runs) => runs.iter().map(|run| run.to_range(page_size)).collect(),
+
+            None => self.ranges.clone(),
+        }
+    }
+
+    /// Merge the ranges recorded for another occurrence of the same file
+    pub fn merge(&mut self, other: &MappedFile, page_size: u64) {
+        self.processes.extend(other.processes.iter().cloned());
+
+        if self.is_whole_file() {
+            return;
+        }
+
+        if other.is_whole_file() {
+            self.ranges.clear();
+            self.resident = None;
+            return;
+        }
+
+        let mut ranges = self.get_wanted_ranges(page_size);
+        ranges.extend(other.get_wanted_ranges(page_size));
+        coalesce_ranges(&mut ranges);
+
+        // an empty residency means nothing was resident, keep it that way
+        if !ranges.is_empty() {
+            self.ranges = ranges;
+            self.resident = None;
+        }
+    }
+
+    /// Whether both record the same version of the same file
+    fn is_same_file(&self, other: &MappedFile) -> bool {
+        self.inode == other.inode
+            && self.device == other.device
+            && self.size == other.size
+            && self.mtime == other.mtime
+    }
+
+    /// Compare the recorded metadata with the current state of the file
+    pub fn check(&self) -> Option<FileState> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => {
+                let unknown = self.inode == 0 && self.device == 0;
+
+                if !unknown
+                    && (metadata.ino() != self.inode
+                        || metadata.dev() != self.device
+                        || metadata.len() != self.size
+                        || metadata.mtime() != self.mtime)
+                {
+                    Some(FileState::Changed)
+                } else {
+                    None
+                }
+            }
+
+            Err(_) => Some(FileState::Missing),
+        }
+    }
+}
+
+/// On-disk representation of a snapshot, version 2.0
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    version: String,
+    enabled: bool,
+    command: String,
+
+    #[serde(default)]
+    priority: i32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    captured_at: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kernel_release: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    identity: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exe: Option<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cmdline: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cgroup: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+
+    #[serde(default, rename = "file")]
+    files: Vec<MappedFile>,
 }
 
 pub struct Snapshot {
     pub enabled: bool,
     pub command: String,
-    pub mappings: HashSet<PathBuf>,
+
+    /// Snapshots with a higher priority are cached and locked first
+    pub priority: i32,
+
+    /// Capture time in seconds since the epoch
+    pub captured_at: Option<u64>,
+    pub kernel_release: Option<String>,
+    pub hostname: Option<String>,
+
+    /// The key of the snapshot, if it is not the command, see `Identity`
+    pub identity: Option<String>,
+
+    /// The process the snapshot has been taken of
+    pub exe: Option<PathBuf>,
+    pub cmdline: Option<Vec<String>>,
+    pub cgroup: Option<String>,
+    pub unit: Option<String>,
+
+    pub mappings: BTreeMap<PathBuf, MappedFile>,
 }
 
 impl Snapshot {
-    pub fn new_from_process(proc: &Process) -> Result<Self, Error> {
-        let command = proc.get_command()?;
-        let mappings = proc.get_mapped_files();
+    fn new<T: Into<String>>(command: T, mappings: BTreeMap<PathBuf, MappedFile>) -> Self {
+        let uts = nix::sys::utsname::uname();
+        let captured_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
 
-        Ok(Snapshot {
+        let mut mappings = mappings;
+        for file in mappings.values_mut() {
+            file.hits = Some(1);
+            file.last_seen = captured_at;
+        }
+
+        Snapshot {
             enabled: true,
-            command,
+            command: command.into(),
+            priority: 0,
+            captured_at,
+            kernel_release: Some(uts.release().to_string()),
+            hostname: Some(uts.nodename().to_string()),
+            identity: None,
+            exe: None,
+            cmdline: None,
+            cgroup: None,
+            unit: None,
             mappings,
-        })
+        }
+    }
+
+    pub fn new_from_process(proc: &Process, identity: &Identity) -> Result<Self, Error> {
+        let command = proc.get_command()?;
+        let files = proc.get_mapped_files();
+
+        let mut ranges: BTreeMap<PathBuf, Vec<MappedRange>> = BTreeMap::new();
+        for mapping in proc.maps.iter() {
+            if let Some(ref file) = mapping.file {
+                if files.contains(file) {
+                    ranges.entry(file.clone()).or_default().push(MappedRange {
+                        offset: mapping.offset,
+                        length: (mapping.end - mapping.start) as u64,
+                    });
+                }
+            }
+        }
+
+        let mappings = ranges
+            .into_iter()
+            .map(|(path, ranges)| (path.clone(), MappedFile::new(path, ranges)))
+            .collect();
+
+        let mut result = Snapshot::new(command, mappings);
+
+        result.exe = proc.get_exe().ok();
+        result.cmdline = proc.get_cmdline().ok();
+        result.cgroup = proc.get_cgroup().ok();
+        result.unit = proc.get_unit().ok();
+        result.set_identity(identity);
+
+        Ok(result)
+    }
+
+    /// Build a single snapshot of a process and all of its descendants, every
+    /// file is tagged with the commands of the processes that mapped it
+    pub fn new_from_process_tree(proc: &Process, identity: &Identity) -> Result<Self, Error> {
+        let descendants = proc.get_descendants();
+
+        let mut processes = vec![proc];
+        processes.extend(descendants.iter());
+
+        Self::new_from_processes(&processes, identity)
+    }
+
+    /// Build a single snapshot of a group of processes, like a process tree or
+    /// the members of a cgroup. The first process names the snapshot, every
+    /// file is tagged with the commands of the processes that mapped it.
+    pub fn new_from_processes(processes: &[&Process], identity: &Identity) -> Result<Self, Error> {
+        let tag = |mut snapshot: Snapshot| {
+            for file in snapshot.mappings.values_mut() {
+                file.processes.insert(snapshot.command.clone());
+            }
+
+            snapshot
+        };
+
+        let (first, rest) = processes.split_first().ok_or(SnapshotError::NoProcesses)?;
+
+        let mut result = tag(Snapshot::new_from_process(first, identity)?);
+
+        for process in rest.iter() {
+            // skip processes that exited meanwhile
+            if let Ok(snapshot) = Snapshot::new_from_process(process, identity) {
+                result.add(tag(snapshot));
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn new_from_trace(trace: &Trace, identity: &Identity) -> Self {
+        let mappings = trace
+            .files
+            .iter()
+            .map(|path| (path.clone(), MappedFile::new(path, vec![])))
+            .collect();
+
+        let mut result = Snapshot::new(trace.command.clone(), mappings);
+
+        result.exe = trace.exe.clone();
+        result.cmdline = Some(trace.cmdline.clone());
+        result.set_identity(identity);
+
+        result
+    }
+
+    /// Build a snapshot of an executable from its shared library dependencies,
+    /// without running it. Returns the snapshot and the names of all libraries
+    /// that could not be resolved.
+    pub fn new_from_elf<T: AsRef<Path>>(
+        path: T,
+        identity: &Identity,
+    ) -> Result<(Self, BTreeSet<String>), Error> {
+        let executable = fs::canonicalize(path.as_ref())?;
+        let (mut files, unresolved) = resolve_dependencies(&executable)?;
+
+        // name the snapshot like the kernel names the process
+        let command: String = path
+            .as_ref()
+            .file_name()
+            .map(|f| {
+                f.to_string_lossy()
+                    .chars()
+                    .take(TASK_COMM_LEN - 1)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        files.insert(executable.clone());
+
+        let mappings = files
+            .into_iter()
+            .map(|path| (path.clone(), MappedFile::new(path, vec![])))
+            .collect();
+
+        let mut result = Snapshot::new(command, mappings);
+
+        result.exe = Some(executable);
+        result.set_identity(identity);
+
+        Ok((result, unresolved))
     }
 
     pub fn new_from_file<T: AsRef<Path>>(path: T) -> Result<Self, Error> {
-        let mut file = BufReader::new(fs::File::open(path.as_ref())?);
+        let content = fs::read_to_string(path.as_ref())?;
 
-        let mut header = String::new();
-        file.read_line(&mut header)?;
+        let header = content.lines().next().unwrap_or_default().trim();
+        if let Some(version) = header.strip_prefix("prefault snapshot:") {
+            let version = version.trim();
+
+            if version != "1.0" {
+                return Err(SnapshotError::FormatError {
+                    path: path.as_ref().to_string_lossy().into(),
+                    version: version.into(),
+                }
+                .into());
+            }
+
+            return Self::parse_v1(content.as_bytes());
+        }
+
+        let value: toml::Value =
+            toml::from_str(&content).map_err(|_| SnapshotError::FormatError {
+                path: path.as_ref().to_string_lossy().into(),
+                version: "unknown".into(),
+            })?;
 
-        if header.trim() != "prefault snapshot: 1.0" {
-            return Err(SnapshotError::FormatError(path.as_ref().to_string_lossy().into()).into());
+        let version = value
+            .get("version")
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown");
+
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::FormatError {
+                path: path.as_ref().to_string_lossy().into(),
+                version: version.into(),
+            }
+            .into());
         }
 
+        let file: SnapshotFile = value.try_into()?;
+
+        Ok(Snapshot {
+            enabled: file.enabled,
+            command: file.command,
+            priority: file.priority,
+            captured_at: file.captured_at,
+            kernel_release: file.kernel_release,
+            hostname: file.hostname,
+            identity: file.identity,
+            exe: file.exe,
+            cmdline: file.cmdline,
+            cgroup: file.cgroup,
+            unit: file.unit,
+            mappings: file
+                .files
+                .into_iter()
+                .map(|f| (f.path.clone(), f))
+                .collect(),
+        })
+    }
+
+    /// Parse a version 1.0 snapshot; the file metadata is filled in from the
+    /// current state of the filesystem, so that it can be upgraded on save
+    fn parse_v1<R: BufRead>(mut file: R) -> Result<Self, Error> {
+        let mut header = String::new();
+        file.read_line(&mut header)?;
+
         let mut enabled_line = String::new();
         file.read_line(&mut enabled_line)?;
 
-        let enabled;
-        if enabled_line.contains("false") {
-            enabled = false
-        } else {
-            enabled = true
-        }
+        let enabled = !enabled_line.contains("false");
 
         let mut command = String::new();
         file.read_line(&mut command)?;
 
         command = command.trim().to_string();
 
-        let mut mappings = HashSet::new();
+        let mut mappings = BTreeMap::new();
 
         for l in file.lines() {
             let value = PathBuf::from(&l?);
-            mappings.insert(value);
+            mappings.insert(value.clone(), MappedFile::new(value, vec![]));
         }
 
         Ok(Snapshot {
             enabled,
             command,
+            priority: 0,
+            captured_at: None,
+            kernel_release: None,
+            hostname: None,
+            identity: None,
+            exe: None,
+            cmdline: None,
+            cgroup: None,
+            unit: None,
             mappings,
         })
     }
 
     pub fn save_to_file<P: AsRef<Path>>(&self, snapshot_dir: P) -> Result<PathBuf, Error> {
-        let path = PathBuf::from(format!("{}.snapshot", hash_string(&self.command)));
-        let path = snapshot_dir.as_ref().join(path);
+        let path = get_snapshot_path(snapshot_dir, self.get_key());
 
-        let mut file = BufWriter::new(fs::File::create(path.clone())?);
+        let snapshot_file = SnapshotFile {
+            version: SNAPSHOT_VERSION.into(),
+            enabled: self.enabled,
+            command: self.command.clone(),
+            priority: self.priority,
+            captured_at: self.captured_at,
+            kernel_release: self.kernel_release.clone(),
+            hostname: self.hostname.clone(),
+            identity: self.identity.clone(),
+            exe: self.exe.clone(),
+            cmdline: self.cmdline.clone(),
+            cgroup: self.cgroup.clone(),
+            unit: self.unit.clone(),
+            files: self.mappings.values().cloned().collect(),
+        };
 
-        writeln!(file, "prefault snapshot: 1.0")?;
-        writeln!(file, "enabled: {}", self.enabled)?;
-        writeln!(file, "{}", self.command)?;
+        // replace the file atomically, the daemon rewrites snapshots while
+        // other commands may be reading them
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, toml::to_string(&snapshot_file)?)?;
+        fs::rename(&tmp, &path)?;
 
-        for mapping in self.mappings.iter() {
-            writeln!(
-                file,
-                "{}",
-                mapping.to_str().expect("Error converting a path to utf-8")
-            )?;
+        Ok(path)
+    }
+
+    /// Record which pages of the mapped files are currently resident in the page cache
+    pub fn record_residency(&mut self) {
+        for file in self.mappings.values_mut() {
+            file.resident = memory::get_resident_pages(&file.path).ok();
         }
+    }
 
-        Ok(path)
+    /// Get all files that are missing or have been changed since the snapshot was taken
+    pub fn get_stale_files(&self) -> Vec<(PathBuf, FileState)> {
+        self.mappings
+            .values()
+            .filter_map(|f| f.check().map(|state| (f.path.clone(), state)))
+            .collect()
+    }
+
+    /// Find the executable that the snapshot has been taken of
+    pub fn guess_executable(&self) -> Option<PathBuf> {
+        if let Some(ref exe) = self.exe {
+            return Some(exe.clone());
+        }
+
+        let executables: Vec<&PathBuf> = self
+            .mappings
+            .keys()
+            .filter(|path| {
+                ElfFile::parse(path)
+                    .map(|elf| elf.interpreter.is_some())
+                    .unwrap_or(false)
+            })
+            .collect();
+
+        // the kernel truncates the command name, so only compare a prefix
+        let by_name = executables.iter().find(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().starts_with(&self.command))
+                .unwrap_or(false)
+        });
+
+        let by_kind = executables.iter().find(|path| {
+            path.file_name()
+                .map(|name| !name.to_string_lossy().contains(".so"))
+                .unwrap_or(false)
+        });
+
+        by_name.or(by_kind).map(|path| (*path).clone())
+    }
+
+    /// Repair stale entries by re-resolving the shared library dependencies
+    /// of the executable. Files that are missing are removed, changed files
+    /// are re-recorded as a whole. Returns the libraries that could not be resolved.
+    pub fn repair_from_elf(&mut self) -> Result<BTreeSet<String>, Error> {
+        let executable = self
+            .guess_executable()
+            .ok_or_else(|| SnapshotError::NoExecutable(self.command.clone()))?;
+
+        let (libraries, unresolved) = resolve_dependencies(&executable)?;
+
+        for (path, state) in self.get_stale_files() {
+            match state {
+                FileState::Missing => {
+                    self.mappings.remove(&path);
+                }
+
+                FileState::Changed => {
+                    self.mappings
+                        .insert(path.clone(), MappedFile::new(path, vec![]));
+                }
+            }
+        }
+
+        for library in libraries {
+            if !self.mappings.contains_key(&library) {
+                self.mappings
+                    .insert(library.clone(), MappedFile::new(library, vec![]));
+            }
+        }
+
+        Ok(unresolved)
+    }
+
+    /// Add the files of another process with the same key, that has been
+    /// captured at the same time
+    pub fn add(&mut self, other: Snapshot) {
+        for (path, file) in other.mappings.into_iter() {
+            match self.mappings.get_mut(&path) {
+                Some(existing) => existing.merge(&file, memory::get_page_size()),
+
+                None => {
+                    self.mappings.insert(path, file);
+                }
+            }
+        }
+    }
+
+    /// Union a new capture into the snapshot. Files that are seen again have
+    /// their hit count incremented, files that have not been seen in
+    /// `expire_after` captures are removed.
+    pub fn merge(&mut self, capture: Snapshot, expire_after: Option<u32>) {
+        for (path, file) in self.mappings.iter_mut() {
+            if !capture.mappings.contains_key(path) {
+                file.missed += 1;
+            }
+        }
+
+        if let Some(expire_after) = expire_after {
+            self.mappings.retain(|_, file| file.missed < expire_after);
+        }
+
+        for (path, mut file) in capture.mappings.into_iter() {
+            if let Some(existing) = self.mappings.get(&path) {
+                file.hits = Some(existing.hits.unwrap_or(1) + 1);
+
+                // ranges recorded for an older version of the file are stale
+                if existing.is_same_file(&file) {
+                    file.merge(existing, memory::get_page_size());
+                }
+            }
+
+            self.mappings.insert(path, file);
+        }
+
+        self.captured_at = capture.captured_at;
+        self.kernel_release = capture.kernel_release;
+        self.hostname = capture.hostname;
+        self.exe = capture.exe;
+        self.cmdline = capture.cmdline;
+        self.cgroup = capture.cgroup;
+        self.unit = capture.unit;
     }
 
     pub fn set_enabled(&mut self, enabled: bool) {
         self.enabled = enabled;
     }
 
-    pub fn get_hash(&self) -> u64 {
-        hash_string(&self.command)
+    pub fn set_priority(&mut self, priority: i32) {
+        self.priority = priority;
+    }
+
+    /// Store the snapshot under `key` instead of the key of its process
+    pub fn set_key<T: Into<String>>(&mut self, key: T) {
+        let key = key.into();
+
+        self.identity = if key != self.command { Some(key) } else { None };
+    }
+
+    pub fn set_identity(&mut self, identity: &Identity) {
+        let key = identity.get_key(
+            &self.command,
+            self.exe.as_deref(),
+            self.cmdline.as_deref(),
+            self.unit.as_deref(),
+        );
+
+        self.set_key(key);
+    }
+
+    /// What the snapshot is stored under, see `Identity`
+    pub fn get_key(&self) -> &str {
+        self.identity.as_ref().unwrap_or(&self.command)
+    }
+
+    pub fn get_hash(&self) -> String {
+        hash_string(self.get_key())
+    }
+
+    pub fn get_paths(&self) -> Vec<PathBuf> {
+        self.mappings.keys().cloned().collect()
+    }
+
+    pub fn get_files(&self) -> Vec<MappedFile> {
+        self.mappings.values().cloned().collect()
     }
 }
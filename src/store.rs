@@ -0,0 +1,92 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use failure::Error;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::snapshot::*;
+
+/// The system snapshot directory, and for unprivileged users their own
+/// snapshot directory layered on top of it. A snapshot in the user store
+/// hides the snapshot with the same name in the system store.
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    system_dir: PathBuf,
+    user_dir: Option<PathBuf>,
+}
+
+impl SnapshotStore {
+    pub fn new<P: AsRef<Path>>(system_dir: P, user_dir: Option<P>) -> Self {
+        SnapshotStore {
+            system_dir: system_dir.as_ref().to_path_buf(),
+            user_dir: user_dir.map(|d| d.as_ref().to_path_buf()),
+        }
+    }
+
+    /// The snapshot directories, in the order in which they are layered
+    pub fn get_dirs(&self) -> Vec<&Path> {
+        let mut result = vec![self.system_dir.as_path()];
+        result.extend(self.user_dir.as_deref());
+
+        result
+    }
+
+    /// Where new and modified snapshots are written to
+    pub fn get_writable_dir(&self) -> &Path {
+        self.user_dir.as_deref().unwrap_or(&self.system_dir)
+    }
+
+    /// The paths of all visible snapshots, ordered by name
+    pub fn get_snapshot_paths(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut result = BTreeMap::new();
+
+        for dir in self.get_dirs() {
+            // a missing directory is an empty store
+            if !dir.exists() {
+                continue;
+            }
+
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension() != Some(OsStr::new("snapshot")) {
+                    continue;
+                }
+
+                if let Some(name) = path.file_name() {
+                    result.insert(name.to_os_string(), path);
+                }
+            }
+        }
+
+        Ok(result.into_values().collect())
+    }
+
+    /// Find the visible snapshot stored under `key`
+    pub fn find<T: AsRef<str>>(&self, key: T) -> Option<PathBuf> {
+        self.get_dirs()
+            .iter()
+            .rev()
+            .map(|dir| get_snapshot_path(dir, key.as_ref()))
+            .find(|path| path.exists())
+    }
+}
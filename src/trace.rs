@@ -0,0 +1,330 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+#![allow(non_local_definitions)]
+
+use failure::{Error, Fail};
+use nix::errno::Errno;
+use nix::sys::ptrace;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{execvp, fork, ForkResult, Pid};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::process::*;
+
+#[derive(Fail, Debug)]
+pub enum TraceError {
+    #[fail(display = "No command specified")]
+    NoCommand,
+
+    #[fail(display = "Could not launch the command: {}", _0)]
+    Spawn(#[fail(cause)] nix::Error),
+
+    #[fail(display = "Could not trace the command: {}", _0)]
+    Ptrace(#[fail(cause)] nix::Error),
+
+    #[fail(display = "Tracing is not supported on this architecture")]
+    UnsupportedArchitecture,
+}
+
+/// The set of files a traced command (and all of its descendants) accessed
+pub struct Trace {
+    pub command: String,
+    pub exe: Option<PathBuf>,
+    pub cmdline: Vec<String>,
+    pub files: HashSet<PathBuf>,
+}
+
+/// Runs a command under ptrace(2) and records the files it opens, executes or maps
+pub struct Tracer {
+    child: Pid,
+    tracees: HashSet<Pid>,
+    in_syscall: HashMap<Pid, bool>,
+    trace: Trace,
+}
+
+extern "C" fn handle_sigalrm(_: libc::c_int) {
+    // only used to interrupt `waitpid()`
+}
+
+impl Tracer {
+    pub fn spawn<T: AsRef<str>>(args: &[T]) -> Result<Self, Error> {
+        if !cfg!(target_arch = "x86_64") {
+            return Err(TraceError::UnsupportedArchitecture.into());
+        }
+
+        if args.is_empty() {
+            return Err(TraceError::NoCommand.into());
+        }
+
+        let args: Vec<CString> = args
+            .iter()
+            .map(|a| CString::new(a.as_ref()))
+            .collect::<Result<_, _>>()?;
+
+        match fork().map_err(TraceError::Spawn)? {
+            ForkResult::Child => {
+                let args: Vec<&std::ffi::CStr> = args.iter().map(|a| a.as_c_str()).collect();
+
+                if ptrace::traceme().is_ok() {
+                    let _ = execvp(args[0], &args);
+                }
+
+                unsafe { libc::_exit(127) }
+            }
+
+            ForkResult::Parent { child } => {
+                // the child stops with a SIGTRAP after the initial `execvp()`
+                match waitpid(child, None).map_err(TraceError::Spawn)? {
+                    WaitStatus::Stopped(_, Signal::SIGTRAP) => {}
+
+                    _ => return Err(TraceError::Spawn(nix::Error::Sys(Errno::ECHILD)).into()),
+                }
+
+                ptrace::setoptions(
+                    child,
+                    ptrace::Options::PTRACE_O_TRACESYSGOOD
+                        | ptrace::Options::PTRACE_O_TRACEFORK
+                        | ptrace::Options::PTRACE_O_TRACEVFORK
+                        | ptrace::Options::PTRACE_O_TRACECLONE
+                        | ptrace::Options::PTRACE_O_TRACEEXEC,
+                )
+                .map_err(TraceError::Ptrace)?;
+
+                let process = Process::new(child.as_raw());
+                let exe = process.as_ref().ok().and_then(|p| p.get_exe().ok());
+                let command = process.and_then(|p| p.get_command()).unwrap_or_else(|_| {
+                    Path::new(args[0].to_str().unwrap_or_default())
+                        .file_name()
+                        .map(|f| f.to_string_lossy().into_owned())
+                        .unwrap_or_default()
+                });
+
+                let mut tracer = Tracer {
+                    child,
+                    tracees: HashSet::new(),
+                    in_syscall: HashMap::new(),
+                    trace: Trace {
+                        command,
+                        exe,
+                        cmdline: args
+                            .iter()
+                            .map(|a| a.to_string_lossy().into_owned())
+                            .collect(),
+                        files: HashSet::new(),
+                    },
+                };
+
+                tracer.tracees.insert(child);
+                tracer.record_mapped_files(child);
+
+                ptrace::syscall(child, None).map_err(TraceError::Ptrace)?;
+
+                Ok(tracer)
+            }
+        }
+    }
+
+    /// Trace until the window elapsed, the traced command exited, or
+    /// `running` is cleared
+    pub fn record(
+        &mut self,
+        window: Option<Duration>,
+        running: &AtomicBool,
+    ) -> Result<&Trace, Error> {
+        let deadline = window.map(|w| Instant::now() + w);
+
+        if let Some(window) = window {
+            let action = SigAction::new(
+                SigHandler::Handler(handle_sigalrm),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            unsafe {
+                signal::sigaction(Signal::SIGALRM, &action).map_err(TraceError::Ptrace)?;
+                libc::alarm(window.as_secs().max(1) as libc::c_uint);
+            }
+        }
+
+        while self.tracees.contains(&self.child) {
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    break;
+                }
+            }
+
+            match waitpid(None, Some(WaitPidFlag::__WALL)) {
+                Ok(status) => self.handle_status(status)?,
+
+                Err(nix::Error::Sys(Errno::EINTR)) => {
+                    if deadline.map(|d| Instant::now() < d).unwrap_or(false) {
+                        unsafe {
+                            libc::alarm(1);
+                        }
+                    }
+                }
+
+                Err(nix::Error::Sys(Errno::ECHILD)) => break,
+
+                Err(e) => return Err(TraceError::Ptrace(e).into()),
+            }
+        }
+
+        unsafe {
+            libc::alarm(0);
+        }
+
+        Ok(&self.trace)
+    }
+
+    /// Stop tracing, the traced command keeps running on its own. Tracees
+    /// that are not in a ptrace-stop can not be detached here, the kernel
+    /// detaches them once prefault exits
+    pub fn release(self) {
+        for pid in self.tracees.iter() {
+            let _ = ptrace::detach(*pid, None);
+        }
+    }
+
+    fn handle_status(&mut self, status: WaitStatus) -> Result<(), Error> {
+        match status {
+            WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _) => {
+                self.tracees.remove(&pid);
+                self.in_syscall.remove(&pid);
+            }
+
+            WaitStatus::PtraceSyscall(pid) => {
+                let exiting = self.in_syscall.get(&pid).cloned().unwrap_or(false);
+                self.in_syscall.insert(pid, !exiting);
+
+                if exiting {
+                    self.record_syscall(pid);
+                }
+
+                self.resume(pid, None)?;
+            }
+
+            WaitStatus::PtraceEvent(pid, _, event) => {
+                if event == libc::PTRACE_EVENT_EXEC {
+                    self.record_mapped_files(pid);
+                }
+
+                self.resume(pid, None)?;
+            }
+
+            WaitStatus::Stopped(pid, sig) => {
+                if self.tracees.insert(pid) && sig == Signal::SIGSTOP {
+                    // initial stop of a newly created child
+                    self.resume(pid, None)?;
+                } else {
+                    self.resume(pid, Some(sig))?;
+                }
+            }
+
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn resume(&self, pid: Pid, sig: Option<Signal>) -> Result<(), Error> {
+        match ptrace::syscall(pid, sig) {
+            // the tracee has been killed in the meantime
+            Err(nix::Error::Sys(Errno::ESRCH)) => Ok(()),
+
+            Err(e) => Err(TraceError::Ptrace(e).into()),
+
+            Ok(()) => Ok(()),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn record_syscall(&mut self, pid: Pid) {
+        let regs = match ptrace::getregs(pid) {
+            Ok(regs) => regs,
+            Err(_) => return,
+        };
+
+        if (regs.rax as i64) < 0 {
+            return; // failed syscall
+        }
+
+        let fd = match regs.orig_rax as libc::c_long {
+            libc::SYS_open | libc::SYS_openat | libc::SYS_creat => regs.rax as i64,
+            libc::SYS_mmap => regs.r8 as i32 as i64,
+            libc::SYS_execve | libc::SYS_execveat => {
+                self.record_file(PathBuf::from(format!("/proc/{}/exe", pid)));
+                return;
+            }
+            _ => return,
+        };
+
+        if fd >= 0 {
+            self.record_file(PathBuf::from(format!("/proc/{}/fd/{}", pid, fd)));
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn record_syscall(&mut self, _pid: Pid) {}
+
+    fn record_mapped_files(&mut self, pid: Pid) {
+        if let Ok(process) = Process::new(pid.as_raw()) {
+            for file in process.get_mapped_files() {
+                self.record_file(file);
+            }
+        }
+    }
+
+    fn record_file(&mut self, link: PathBuf) {
+        let path = match fs::read_link(&link) {
+            Ok(path) => path,
+            Err(_) => link,
+        };
+
+        if is_cacheable_file(&path) {
+            self.trace.files.insert(path);
+        }
+    }
+}
+
+fn is_cacheable_file<T: AsRef<Path>>(path: T) -> bool {
+    let path = path.as_ref();
+
+    if !path.is_absolute()
+        || path.starts_with("/proc")
+        || path.starts_with("/sys")
+        || path.starts_with("/dev")
+        || path.to_string_lossy().ends_with("(deleted)")
+    {
+        return false;
+    }
+
+    fs::metadata(path).map(|m| m.is_file()).unwrap_or(false)
+}
@@ -18,18 +18,72 @@
     along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-
 use pretty_bytes::converter::convert;
 
-pub fn hash_string<T: Hash>(s: T) -> u64 {
-    let mut hasher = DefaultHasher::new();
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// The 64 bit FNV-1a hash of the UTF-8 bytes of `s`, as 16 lowercase hex
+/// digits. Snapshot file names are derived from it, so it must never change.
+pub fn hash_string<T: AsRef<str>>(s: T) -> String {
+    let hash = s.as_ref().bytes().fold(FNV_OFFSET_BASIS, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(FNV_PRIME)
+    });
 
-    s.hash(&mut hasher);
-    hasher.finish()
+    format!("{:016x}", hash)
+}
+
+/// Whether `s` looks like the output of `hash_string`
+pub fn is_hash_string(s: &str) -> bool {
+    s.len() == 16
+        && s.bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
 }
 
 pub fn format_file_size(size: u64) -> String {
     convert(size as f64)
 }
+
+/// Parse a size like `4096`, `512K`, `100M` or `2G`, in powers of 1024
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+
+        None => (s, ""),
+    };
+
+    let multiplier: u64 = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        "T" | "TB" | "TIB" => 1024 * 1024 * 1024 * 1024,
+
+        _ => return None,
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Parse a duration like `30s`, `15m`, `12h`, `7d` or `2w` into seconds
+pub fn parse_duration(s: &str) -> Option<i64> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+
+        None => (s, "s"),
+    };
+
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+
+        _ => return None,
+    };
+
+    number.parse::<i64>().ok()?.checked_mul(multiplier)
+}
@@ -0,0 +1,131 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::memory::{self, Alignment, MemoryError, Residency};
+use crate::snapshot::MappedFile;
+use crate::workers::Workers;
+
+/// When and how much `prefault cache --watch` faults in again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchLimits {
+    /// How long to wait between two checks
+    pub interval: Duration,
+
+    /// Files are faulted in again when less than this share of their
+    /// ranges, in percent, is resident in the page cache
+    pub threshold: f64,
+
+    /// The number of bytes that may be read per check, unlimited if not set
+    pub io_budget: Option<u64>,
+}
+
+/// A file that has been evicted from the page cache, at least in part
+#[derive(Debug, Clone)]
+pub struct ColdFile {
+    pub file: MappedFile,
+    pub residency: Residency,
+}
+
+impl ColdFile {
+    /// The number of bytes that have to be read to fault the file in again
+    pub fn get_missing_size(&self) -> u64 {
+        self.residency
+            .size
+            .saturating_sub(self.residency.resident_pages * memory::get_page_size())
+    }
+}
+
+/// What a single check of `rewarm` did
+#[derive(Debug, Default)]
+pub struct RewarmReport {
+    /// The number of files whose residency has been checked
+    pub checked: usize,
+
+    /// The files that have been faulted in again
+    pub rewarmed: Vec<ColdFile>,
+
+    /// Files that did not fit into the I/O budget, they are checked again
+    /// on the next run
+    pub deferred: Vec<ColdFile>,
+
+    pub errors: Vec<(PathBuf, MemoryError)>,
+}
+
+/// Check the page cache residency of the files with mincore(2), and fault
+/// in the files whose residency dropped below the threshold again. Files
+/// are taken in the given order until the I/O budget is used up, the first
+/// file is faulted in even if it is larger than the budget.
+pub fn rewarm(
+    files: &[MappedFile],
+    alignment: Alignment,
+    limits: &WatchLimits,
+    workers: &Workers,
+) -> RewarmReport {
+    let mut report = RewarmReport {
+        checked: files.len(),
+        ..Default::default()
+    };
+
+    let mut budget = limits.io_budget;
+
+    for file in files.iter() {
+        let residency = match memory::get_mapping_residency(file, alignment) {
+            Ok(residency) => residency,
+
+            Err(e) => {
+                report.errors.push((file.path.clone(), e));
+                continue;
+            }
+        };
+
+        if residency.size == 0 || (residency.get_percentage() as f64) >= limits.threshold {
+            continue;
+        }
+
+        let cold = ColdFile {
+            file: file.clone(),
+            residency,
+        };
+
+        let missing = cold.get_missing_size();
+
+        budget = match budget {
+            Some(left) if missing > left && !report.rewarmed.is_empty() => {
+                report.deferred.push(cold);
+                continue;
+            }
+
+            Some(left) => Some(left.saturating_sub(missing)),
+            None => None,
+        };
+
+        report.rewarmed.push(cold);
+    }
+
+    let files: Vec<MappedFile> = report.rewarmed.iter().map(|c| c.file.clone()).collect();
+    report
+        .errors
+        .extend(memory::prefault_file_mappings(&files, alignment, workers));
+
+    report
+}
@@ -0,0 +1,239 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+#![allow(non_local_definitions)]
+
+use failure::{Error, Fail};
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::layout::IoOrder;
+
+/// From linux/ioprio.h
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+const IOPRIO_CLASS_BE: libc::c_int = 2;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// The priority level of the best-effort class, if none is given
+const DEFAULT_BE_LEVEL: u8 = 4;
+
+#[derive(Fail, Debug)]
+pub enum WorkersError {
+    #[fail(display = "Could not set the I/O priority: {}", _0)]
+    IoPriority(#[fail(cause)] io::Error),
+
+    #[fail(display = "Could not set the nice value: {}", _0)]
+    Nice(#[fail(cause)] io::Error),
+
+    #[fail(display = "Could not start the worker threads: {}", _0)]
+    ThreadPool(String),
+}
+
+/// The I/O scheduling class of the workers, see ioprio_set(2)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IoPriority {
+    /// Only get disk time when no other process needs it
+    Idle,
+
+    /// The default class, with a level from 0 (highest) to 7 (lowest)
+    BestEffort(u8),
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid I/O priority '{}', expected idle or best-effort with an optional level like best-effort:7",
+                s
+            )
+        };
+
+        match s.trim() {
+            "idle" => Ok(IoPriority::Idle),
+            "best-effort" => Ok(IoPriority::BestEffort(DEFAULT_BE_LEVEL)),
+
+            other => match other.strip_prefix("best-effort:").map(|l| l.parse::<u8>()) {
+                Some(Ok(level)) if level <= 7 => Ok(IoPriority::BestEffort(level)),
+
+                _ => Err(invalid()),
+            },
+        }
+    }
+}
+
+impl IoPriority {
+    fn get_value(self) -> libc::c_int {
+        match self {
+            IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+
+            IoPriority::BestEffort(level) => {
+                (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level)
+            }
+        }
+    }
+}
+
+/// How the files are read while prefaulting, everything is left to the
+/// defaults if not set
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoLimits {
+    pub io_priority: Option<IoPriority>,
+
+    /// The nice value of the workers, from -20 to 19
+    pub nice: Option<i32>,
+
+    /// The number of bytes that may be read per second, by all workers
+    pub max_read_rate: Option<u64>,
+
+    /// The number of worker threads, the number of CPUs if not set
+    pub max_concurrency: Option<usize>,
+
+    /// The order in which the files are read
+    pub io_order: IoOrder,
+}
+
+/// Apply the I/O priority and the nice value to the calling thread
+fn apply_to_current_thread(limits: &IoLimits) -> Result<(), WorkersError> {
+    if let Some(priority) = limits.io_priority {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                priority.get_value(),
+            )
+        };
+        if result != 0 {
+            return Err(WorkersError::IoPriority(io::Error::last_os_error()));
+        }
+    }
+
+    if let Some(nice) = limits.nice {
+        // on Linux, this only applies to the calling thread
+        let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+        if result != 0 {
+            return Err(WorkersError::Nice(io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Limits the number of bytes read per second, shared by all workers
+#[derive(Debug)]
+struct RateLimiter {
+    rate: u64,
+
+    /// When the limiter has been started, and the bytes read since
+    state: Mutex<(Instant, u64)>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate: rate.max(1),
+            state: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Account for `bytes` that are about to be read, and wait until
+    /// reading them does not exceed the rate
+    fn acquire(&self, bytes: u64) {
+        let delay = {
+            let mut state = self.state.lock().unwrap();
+
+            // start over after an idle period, so that the budget of the
+            // idle time is not spent all at once
+            let due = Duration::from_secs_f64(state.1 as f64 / self.rate as f64);
+            if state.0.elapsed() > due + Duration::from_secs(1) {
+                *state = (Instant::now(), 0);
+            }
+
+            state.1 += bytes;
+
+            Duration::from_secs_f64(state.1 as f64 / self.rate as f64)
+                .checked_sub(state.0.elapsed())
+        };
+
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// The threads that fault in files, with their I/O priority, nice value,
+/// read rate, number and order applied
+#[derive(Debug)]
+pub struct Workers {
+    pool: rayon::ThreadPool,
+    limiter: Option<RateLimiter>,
+    order: IoOrder,
+}
+
+impl Workers {
+    /// Start the worker threads. The I/O priority and the nice value are
+    /// applied to the calling thread as well, which looks up the files.
+    pub fn new(limits: &IoLimits) -> Result<Self, Error> {
+        apply_to_current_thread(limits)?;
+
+        let thread_limits = *limits;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(limits.max_concurrency.unwrap_or(0))
+            .thread_name(|index| format!("prefault-worker-{}", index))
+            .start_handler(move |_| {
+                // the calling thread accepted the same settings
+                let _ = apply_to_current_thread(&thread_limits);
+            })
+            .build()
+            .map_err(|e| WorkersError::ThreadPool(e.to_string()))?;
+
+        Ok(Workers {
+            pool,
+            limiter: limits.max_read_rate.map(RateLimiter::new),
+            order: limits.io_order,
+        })
+    }
+
+    /// Run `op` in the thread pool of the workers
+    pub fn install<OP, R>(&self, op: OP) -> R
+    where
+        OP: FnOnce() -> R + Send,
+        R: Send,
+    {
+        self.pool.install(op)
+    }
+
+    pub fn get_order(&self) -> IoOrder {
+        self.order
+    }
+
+    /// Wait until `bytes` may be read without exceeding the read rate
+    pub fn acquire(&self, bytes: u64) {
+        if let Some(ref limiter) = self.limiter {
+            limiter.acquire(bytes);
+        }
+    }
+}
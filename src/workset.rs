@@ -0,0 +1,252 @@
+/*
+    prefault
+    Copyright (c) 2019-2020 the prefault developers
+
+    This file is part of prefault.
+
+    Prefault is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Prefault is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Prefault.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use crate::memory::{self, Alignment};
+use crate::snapshot::*;
+
+/// A snapshot or static file list that contributes files to a work set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub path: PathBuf,
+    pub name: String,
+    pub priority: i32,
+    pub files: Vec<MappedFile>,
+}
+
+/// The files of a source that did not fit into the memory budget
+#[derive(Debug, Clone)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub name: String,
+    pub files: usize,
+    pub size: u64,
+}
+
+/// The files of all snapshots and static file lists, merged so that every
+/// file is only processed once, and cut off at the memory budget
+#[derive(Debug, Clone)]
+pub struct WorkSet {
+    /// In priority order, every file belongs to the source with the
+    /// highest priority that references it
+    pub sources: Vec<Source>,
+    pub skipped: Vec<Skipped>,
+
+    /// The number of bytes that will be faulted in or locked
+    pub size: u64,
+
+    /// Occurrences of files that have been merged with another occurrence of
+    /// the same file, and the number of bytes that would have been read again
+    pub duplicates: usize,
+    pub duplicate_size: u64,
+}
+
+/// Identifies a file, so that hard links and symlinks to it collapse
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum FileKey {
+    Inode {
+        device: u64,
+        inode: u64,
+    },
+
+    /// Used for files that are currently missing
+    Path(PathBuf),
+}
+
+impl FileKey {
+    fn new(file: &MappedFile) -> Self {
+        match fs::metadata(&file.path) {
+            Ok(metadata) => FileKey::Inode {
+                device: metadata.dev(),
+                inode: metadata.ino(),
+            },
+
+            Err(_) => FileKey::Path(file.path.clone()),
+        }
+    }
+}
+
+impl WorkSet {
+    pub fn new(mut sources: Vec<Source>, alignment: Alignment, budget: Option<u64>) -> Self {
+        // the sort is stable, so sources of equal priority keep their order
+        sources.sort_by_key(|s| std::cmp::Reverse(s.priority));
+
+        let mut merged: Vec<Source> = Vec::with_capacity(sources.len());
+        let mut seen: HashMap<FileKey, (usize, usize)> = HashMap::new();
+
+        let mut duplicates = 0;
+        let mut duplicate_size = 0;
+
+        for mut source in sources.into_iter() {
+            let index = merged.len();
+            let mut files: Vec<MappedFile> = vec![];
+
+            source.files.sort_by(|a, b| a.path.cmp(&b.path));
+
+            for file in source.files.drain(..) {
+                let key = FileKey::new(&file);
+
+                match seen.get(&key) {
+                    Some(&(s, f)) => {
+                        duplicates += 1;
+                        duplicate_size += get_cost(&file, alignment);
+
+                        if s == index {
+                            files[f].merge(&file, memory::get_page_size());
+                        } else {
+                            merged[s].files[f].merge(&file, memory::get_page_size());
+                        }
+                    }
+
+                    None => {
+                        seen.insert(key, (index, files.len()));
+                        files.push(file);
+                    }
+                }
+            }
+
+            source.files = files;
+            merged.push(source);
+        }
+
+        let mut result = WorkSet {
+            sources: vec![],
+            skipped: vec![],
+            size: 0,
+            duplicates,
+            duplicate_size,
+        };
+
+        let mut exhausted = false;
+        for mut source in merged.into_iter() {
+            let mut skipped = Skipped {
+                path: source.path.clone(),
+                name: source.name.clone(),
+                files: 0,
+                size: 0,
+            };
+
+            let mut files = vec![];
+            for file in source.files.drain(..) {
+                let size = get_cost(&file, alignment);
+
+                if exhausted || budget.map(|b| result.size + size > b).unwrap_or(false) {
+                    exhausted = true;
+
+                    skipped.files += 1;
+                    skipped.size += size;
+                } else {
+                    result.size += size;
+                    files.push(file);
+                }
+            }
+
+            if skipped.files > 0 {
+                result.skipped.push(skipped);
+            }
+
+            if !files.is_empty() {
+                source.files = files;
+                result.sources.push(source);
+            }
+        }
+
+        result
+    }
+
+    /// Cut the work set off at `budget`, the files that do not fit anymore
+    /// are moved to the returned work set
+    pub fn split_off(&mut self, budget: u64, alignment: Alignment) -> WorkSet {
+        let mut rest = WorkSet {
+            sources: vec![],
+            skipped: vec![],
+            size: 0,
+            duplicates: 0,
+            duplicate_size: 0,
+        };
+
+        let mut size = 0;
+        let mut exhausted = false;
+        for source in self.sources.iter_mut() {
+            let mut files = vec![];
+            let mut left = vec![];
+
+            for file in source.files.drain(..) {
+                let cost = get_cost(&file, alignment);
+
+                if exhausted || size + cost > budget {
+                    exhausted = true;
+
+                    rest.size += cost;
+                    left.push(file);
+                } else {
+                    size += cost;
+                    files.push(file);
+                }
+            }
+
+            source.files = files;
+
+            if !left.is_empty() {
+                rest.sources.push(Source {
+                    path: source.path.clone(),
+                    name: source.name.clone(),
+                    priority: source.priority,
+                    files: left,
+                });
+            }
+        }
+
+        self.sources.retain(|s| !s.files.is_empty());
+        self.size = size;
+
+        rest
+    }
+
+    pub fn get_files(&self) -> Vec<MappedFile> {
+        self.sources
+            .iter()
+            .flat_map(|s| s.files.iter().cloned())
+            .collect()
+    }
+
+    pub fn get_paths(&self) -> Vec<PathBuf> {
+        self.sources
+            .iter()
+            .flat_map(|s| s.files.iter().map(|f| f.path.clone()))
+            .collect()
+    }
+}
+
+/// The number of bytes of a file that will be faulted in
+fn get_cost(file: &MappedFile, alignment: Alignment) -> u64 {
+    match fs::metadata(&file.path) {
+        Ok(metadata) => memory::get_aligned_ranges(file, metadata.len(), alignment)
+            .iter()
+            .map(|r| r.length)
+            .sum(),
+
+        Err(_) => 0,
+    }
+}
@@ -32,8 +32,10 @@ cargo build --all --release --verbose
 %install
 %{__mkdir_p} %{buildroot}%{_mandir}/man1
 %{__mkdir_p} %{buildroot}%{_unitdir}/
+%{__mkdir_p} %{buildroot}%{_userunitdir}/
 %{__mkdir_p} %{buildroot}%{_sysconfdir}/%{OrigName}
 %{__mkdir_p} %{buildroot}%{_sysconfdir}/%{OrigName}/cache.d
+%{__mkdir_p} %{buildroot}%{_sysconfdir}/%{OrigName}/prefault.conf.d
 %{__mkdir_p} %{buildroot}%{_sharedstatedir}/%{OrigName}/
 %{__mkdir_p} %{buildroot}%{_sharedstatedir}/%{OrigName}/snapshots/
 #%{__mkdir_p} %{buildroot}%{_datarootdir}/bash-completion/completions/
@@ -42,6 +44,8 @@ install -Dp -m 0755 %{_builddir}/%{OrigName}-master/target/release/prefault %{bu
 cp -a %{_builddir}/%{OrigName}-master/support/man/prefault.1 %{buildroot}/%{_mandir}/man1/prefault.1
 cp -a %{_builddir}/%{OrigName}-master/support/config/prefault.conf %{buildroot}/%{_sysconfdir}/%{OrigName}/prefault.conf
 cp -a %{_builddir}/%{OrigName}-master/support/systemd/prefault.service %{buildroot}/%{_unitdir}/prefault.service
+cp -a %{_builddir}/%{OrigName}-master/support/systemd/prefault-daemon.service %{buildroot}/%{_unitdir}/prefault-daemon.service
+cp -a %{_builddir}/%{OrigName}-master/support/systemd/prefault-user.service %{buildroot}/%{_userunitdir}/prefault.service
 
 %postun
 %systemd_postun_with_restart %{OrigName}.service
@@ -50,11 +54,14 @@ cp -a %{_builddir}/%{OrigName}-master/support/systemd/prefault.service %{buildro
 %license LICENSE
 %doc %{_mandir}/man1/prefault.1.gz
 %dir %{_sysconfdir}/%{OrigName}/cache.d
+%dir %{_sysconfdir}/%{OrigName}/prefault.conf.d
 #%dir %{_datarootdir}/bash-completion/completions/
 #%dir %{_datarootdir}/zsh/site-functions/
 %config(noreplace) %{_sysconfdir}/%{OrigName}/prefault.conf
 %{_bindir}/prefault
 %{_unitdir}/prefault.service
+%{_unitdir}/prefault-daemon.service
+%{_userunitdir}/prefault.service
 %{_sharedstatedir}/%{OrigName}/
 %{_sharedstatedir}/%{OrigName}/snapshots/
 #%{_datarootdir}/bash-completion/completions/prefault